@@ -1,27 +1,27 @@
+#![allow(clippy::empty_line_after_doc_comments, clippy::useless_format)]
+
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use nightlog_common::{log_insertion, mongodb_connection, Log, ObservationRequest};
-use serde::Serialize;
+use nightlog_common::{
+    connect_with_retry, log_insertion, with_retry, ApiResponse, Log, ObservationRequest, CONFIG,
+};
 
 /// Requests come into the runtime as unicode
 /// strings in json format, which can map to any structure that implements `serde::Deserialize`
 /// The runtime pays no attention to the contents of the request payload.
 
-/// This is a made-up example of what a response structure may look like.
-/// There is no restriction on what it can be. The runtime requires responses
-/// to be serialized into json. The runtime pays no attention
-/// to the contents of the response payload.
-#[allow(non_snake_case)]
-#[derive(Serialize)]
-struct Response {
-    statusCode: i32,
-    body: String,
-}
-
 /// This is the main body for the function.
-async fn function_handler(event: LambdaEvent<ObservationRequest>) -> Result<Response, Error> {
-    let mongodb_client = mongodb_connection().await?;
-    let log = Log::from_observation_request(&event.payload);
-    let res = log_insertion(&log, &mongodb_client).await?;
+async fn function_handler(event: LambdaEvent<serde_json::Value>) -> Result<ApiResponse, Error> {
+    let req = match ObservationRequest::from_json(event.payload, CONFIG.features.strict_requests) {
+        Ok(req) => req,
+        Err(error) => return Ok(ApiResponse::new(400, serde_json::to_string(&[error])?)),
+    };
+    // the client gets every invalid field at once, as a list like the other 400 answers
+    if let Err(errors) = req.validate() {
+        return Ok(ApiResponse::new(400, serde_json::to_string(&errors)?));
+    }
+    let mongodb_client = connect_with_retry().await?;
+    let log = Log::from_observation_request(&req);
+    let res = with_retry(|| log_insertion(&log, &mongodb_client)).await?;
     let Some(id) = res else {
         return Err(format!("no id returned from insert operation").into());
     };
@@ -29,12 +29,9 @@ async fn function_handler(event: LambdaEvent<ObservationRequest>) -> Result<Resp
         return Err(format!("couldn't serialize object id").into());
     };
     // Prepare the response
-    let resp = Response {
-        statusCode: 200,
-        body: string_id,
-    };
+    let resp = ApiResponse::new(200, string_id);
 
-    // Return `Response` (it will be serialized to JSON automatically by the runtime)
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
     Ok(resp)
 }
 
@@ -0,0 +1,103 @@
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nightlog_common::{
+    backup_user_logs, connect_with_retry, with_retry, ApiResponse, BackupRequest, UserId,
+};
+use std::path::Path;
+
+// Requests come into the runtime as unicode
+// strings in json format, which can map to any structure that implements `serde::Deserialize`
+// The runtime pays no attention to the contents of the request payload.
+
+// /tmp is the only place a function can write to, and an execution environment runs a
+// single invocation at a time
+const BACKUP_PATH: &str = "/tmp/backup.ndjson";
+
+/// This is the main body for the function.
+/// The backup goes to a file as the logs are read, instead of memory, and is stored from
+/// there: S3 wants to know the length of the object up front.
+async fn function_handler(event: LambdaEvent<BackupRequest>) -> Result<ApiResponse, Error> {
+    let mongodb_client = connect_with_retry().await?;
+    let backup_req = event.payload;
+    // each attempt starts the file over, a failed one can't leave a partial backup behind
+    let count = with_retry(|| async {
+        let file = tokio::fs::File::create(BACKUP_PATH).await?;
+        let writer = tokio::io::BufWriter::new(file);
+        backup_user_logs(&mongodb_client, &backup_req.user_id, writer).await
+    })
+    .await?;
+    store(&backup_req.user_id, Path::new(BACKUP_PATH), count).await
+}
+
+#[cfg(feature = "s3")]
+fn backup_key(user_id: &UserId, at: chrono::DateTime<chrono::Utc>) -> String {
+    format!("{}/{}.ndjson", user_id, at.format("%Y%m%dT%H%M%SZ"))
+}
+
+#[cfg(feature = "s3")]
+async fn store(user_id: &UserId, ndjson: &Path, count: u64) -> Result<ApiResponse, Error> {
+    let bucket =
+        std::env::var("BACKUP_BUCKET").map_err(|_| "BACKUP_BUCKET must be set in environment")?;
+    let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+    let s3_client = aws_sdk_s3::Client::new(&config);
+    let key = backup_key(user_id, chrono::Utc::now());
+    s3_client
+        .put_object()
+        .bucket(bucket)
+        .key(&key)
+        .content_type("application/x-ndjson")
+        .body(aws_sdk_s3::primitives::ByteStream::from_path(ndjson).await?)
+        .send()
+        .await?;
+    let body = serde_json::json!({"key": key, "logs": count});
+    Ok(ApiResponse::new(200, body.to_string()))
+}
+
+// without S3 the backup comes back in the body, handy to run the function locally
+#[cfg(not(feature = "s3"))]
+async fn store(_user_id: &UserId, ndjson: &Path, _count: u64) -> Result<ApiResponse, Error> {
+    let body = tokio::fs::read_to_string(ndjson).await?;
+    Ok(ApiResponse::new(200, body).with_header("Content-Type", "application/x-ndjson"))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use nightlog_common::UserId;
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn backup_key_test() {
+        use chrono::TimeZone;
+        let at = chrono::Utc.with_ymd_and_hms(2024, 5, 1, 22, 0, 0).unwrap();
+        let user_id: UserId = "fake_id".into();
+        assert_eq!(
+            crate::backup_key(&user_id, at),
+            "fake_id/20240501T220000Z.ndjson"
+        );
+    }
+
+    #[cfg(not(feature = "s3"))]
+    #[tokio::test]
+    async fn body_backup_test() {
+        let user_id: UserId = "fake_id".into();
+        let ndjson = std::env::temp_dir().join("body_backup_test.ndjson");
+        std::fs::write(&ndjson, b"{\"objectName\":\"M31\"}\n").unwrap();
+        let resp = crate::store(&user_id, &ndjson, 1).await.unwrap();
+        std::fs::remove_file(&ndjson).unwrap();
+        assert_eq!(resp.statusCode, 200);
+        assert_eq!(resp.body.lines().count(), 1);
+        assert_eq!(resp.headers["Content-Type"], "application/x-ndjson");
+    }
+}
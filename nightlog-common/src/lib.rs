@@ -1,14 +1,24 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use dotenv::dotenv;
+use futures::TryStreamExt;
 use mongodb::{
-    bson::{doc, oid::ObjectId, Bson},
-    options::{ClientOptions, ServerApi, ServerApiVersion},
+    bson::{doc, oid::ObjectId, Bson, Document, Regex},
+    error::ErrorKind,
+    options::{
+        ClientOptions, Collation, CollationStrength, FindOptions, IndexOptions, ReturnDocument,
+        ServerApi, ServerApiVersion,
+    },
     results::{DeleteResult, UpdateResult},
-    Client, Collection, Cursor,
+    Client, Collection, Cursor, IndexModel,
 };
 use once_cell::sync::Lazy;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::{collections::HashMap, env, fmt, future::IntoFuture};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::OnceCell,
+};
 
 // ENVIRONMENT
 
@@ -17,6 +27,17 @@ pub struct Config {
     pub database_url: String,
     pub database_name: String,
     pub database_collection: String,
+    pub features: Features,
+    pub audit_collection: String,
+    pub archive_collection: String,
+    pub prefs_collection: String,
+    pub profiles_collection: String,
+    pub delete_batch_size: i64,
+    pub max_import: usize,
+    /// how long a single CRUD operation may take
+    pub operation_timeout: std::time::Duration,
+    /// the name the connections are shown with on the server
+    pub app_name: String,
 }
 
 // Lazy static configuration that loads only once
@@ -24,470 +45,8738 @@ pub static CONFIG: Lazy<Config> = Lazy::new(|| {
     // Load .env file
     dotenv().ok();
 
-    Config {
-        database_url: env::var("DATABASE_URL").expect("DATABASE_URL must be set in environment"),
-        database_name: env::var("DATABASE_NAME").expect("DATABASE_NAME must be set in environment"),
-        database_collection: env::var("DATABASE_COLLECTION")
-            .expect("DATABASE_COLLECTION must be set in environment"),
-    }
+    Config::from_vars(env::vars())
 });
 
+impl Config {
+    /// Reads the configuration from the given variables, the environment ones for `CONFIG`.
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Config {
+        let vars: HashMap<String, String> = vars.into_iter().collect();
+        let var = |name: &str| vars.get(name).cloned();
+        Config {
+            database_url: var("DATABASE_URL").expect("DATABASE_URL must be set in environment"),
+            database_name: resolve_database_name(
+                var("DATABASE_NAME"),
+                var("DATABASE_BASE_NAME"),
+                var("DEPLOY_ENV"),
+            )
+            .expect("DATABASE_NAME or DEPLOY_ENV must be set in environment"),
+            database_collection: var("DATABASE_COLLECTION")
+                .expect("DATABASE_COLLECTION must be set in environment"),
+            features: Features::from_vars(vars.clone()),
+            audit_collection: var("AUDIT_COLLECTION").unwrap_or_else(|| "audit".to_string()),
+            archive_collection: var("ARCHIVE_COLLECTION").unwrap_or_else(|| "archive".to_string()),
+            prefs_collection: var("PREFS_COLLECTION").unwrap_or_else(|| "prefs".to_string()),
+            profiles_collection: var("PROFILES_COLLECTION")
+                .unwrap_or_else(|| "equipment_profiles".to_string()),
+            delete_batch_size: var("DELETE_BATCH_SIZE")
+                .and_then(|size| size.parse().ok())
+                .filter(|size| *size > 0)
+                .unwrap_or(500),
+            max_import: var("NIGHTLOG_MAX_IMPORT")
+                .and_then(|size| size.parse().ok())
+                .filter(|size| *size > 0)
+                .unwrap_or(500),
+            operation_timeout: std::time::Duration::from_millis(
+                var("OPERATION_TIMEOUT_MS")
+                    .and_then(|millis| millis.parse().ok())
+                    .filter(|millis| *millis > 0)
+                    .unwrap_or(2_500),
+            ),
+            app_name: resolve_app_name(var("NIGHTLOG_APP_NAME")),
+        }
+    }
+}
+
+/// Feature toggles, each read from a `NIGHTLOG_FEATURE_<NAME>` variable set to `true` or `1`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Features {
+    /// record the fields changed by every replacement
+    pub audit: bool,
+    /// reject request fields the request type doesn't know
+    pub strict_requests: bool,
+    /// keep deleted logs, marked with `deleted_at`
+    pub soft_delete: bool,
+    /// refuse to save logs dated after the current time
+    pub reject_future_dates: bool,
+}
+
+impl Features {
+    const PREFIX: &'static str = "NIGHTLOG_FEATURE_";
+
+    // AUDIT_ENABLED and STRICT_REQUESTS predate the registry and are still honored
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Features {
+        let mut features = Features::default();
+        for (name, value) in vars {
+            if value != "true" && value != "1" {
+                continue;
+            }
+            let name = match name.as_str() {
+                "AUDIT_ENABLED" => "AUDIT",
+                "STRICT_REQUESTS" => "STRICT_REQUESTS",
+                name => match name.strip_prefix(Self::PREFIX) {
+                    Some(name) => name,
+                    None => continue,
+                },
+            };
+            match name {
+                "AUDIT" => features.audit = true,
+                "STRICT_REQUESTS" => features.strict_requests = true,
+                "SOFT_DELETE" => features.soft_delete = true,
+                "REJECT_FUTURE_DATES" => features.reject_future_dates = true,
+                _ => {}
+            }
+        }
+        features
+    }
+}
+
+// An explicit DATABASE_NAME wins, otherwise the name is derived from the deploy
+// environment, e.g. `nightlog_staging` or `nightlog_prod`
+pub fn resolve_database_name(
+    database_name: Option<String>,
+    base_name: Option<String>,
+    deploy_env: Option<String>,
+) -> Option<String> {
+    if database_name.is_some() {
+        return database_name;
+    }
+    let base_name = base_name.unwrap_or_else(|| "nightlog".to_string());
+    deploy_env.map(|deploy_env| format!("{}_{}", base_name, deploy_env.to_lowercase()))
+}
+
+/// The app name set on the connections, "nightlog" unless a non empty one is given.
+pub fn resolve_app_name(app_name: Option<String>) -> String {
+    app_name
+        .filter(|app_name| !app_name.trim().is_empty())
+        .unwrap_or_else(|| "nightlog".to_string())
+}
+
 // Function to ensure environment is loaded
 pub fn init() {
     // Force loading of CONFIG if it hasn't been loaded yet
     Lazy::force(&CONFIG);
 }
 
+// SERIALIZATION
+/// Dates can be sent either as unix timestamps or as RFC-3339 strings, they are sent back
+/// as RFC-3339 strings with milliseconds.
+pub mod flexible_date {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use mongodb::bson::Bson;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        date: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&date.to_rfc3339_opts(SecondsFormat::Millis, true))
+    }
+
+    // logs stored before the bson dates still hold unix timestamps
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        from_bson(Bson::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+
+    pub(crate) fn from_bson(value: Bson) -> Result<DateTime<Utc>, String> {
+        let date = match value {
+            Bson::DateTime(date) => DateTime::from_timestamp_millis(date.timestamp_millis()),
+            Bson::Int32(secs) => DateTime::from_timestamp(secs as i64, 0),
+            Bson::Int64(secs) => DateTime::from_timestamp(secs, 0),
+            Bson::String(date) => {
+                return DateTime::parse_from_rfc3339(&date)
+                    .map(|date| date.with_timezone(&Utc))
+                    .map_err(|e| e.to_string())
+            }
+            other => {
+                return Err(format!(
+                    "expected a unix timestamp or an RFC-3339 date string, found {}",
+                    other
+                ))
+            }
+        };
+        date.ok_or_else(|| "date out of range".to_string())
+    }
+}
+
+/// `flexible_date` for the dates a request or a response may not have, a missing or null
+/// one is `None`.
+pub mod flexible_date_option {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use mongodb::bson::Bson;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        date: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match date {
+            Some(date) => {
+                serializer.serialize_some(&date.to_rfc3339_opts(SecondsFormat::Millis, true))
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        match Option::<Bson>::deserialize(deserializer)? {
+            None | Some(Bson::Null) => Ok(None),
+            Some(value) => super::flexible_date::from_bson(value)
+                .map(Some)
+                .map_err(de::Error::custom),
+        }
+    }
+}
+
+/// Dates as the database stores them: a native bson date with milliseconds, whatever
+/// the serializer. They are read like `flexible_date`, so that older logs still parse.
+pub mod bson_date {
+    use chrono::{DateTime, Utc};
+    use mongodb::bson;
+    use serde::{Serialize, Serializer};
+
+    pub use super::flexible_date::deserialize;
+
+    pub fn serialize<S: Serializer>(
+        date: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        bson::DateTime::from_millis(date.timestamp_millis()).serialize(serializer)
+    }
+}
+
+/// `bson_date` for the dates a log may not have, a missing or null one is `None`.
+pub mod bson_date_option {
+    use chrono::{DateTime, Utc};
+    use mongodb::bson::{self, Bson};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        date: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match date {
+            Some(date) => {
+                serializer.serialize_some(&bson::DateTime::from_millis(date.timestamp_millis()))
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        match Option::<Bson>::deserialize(deserializer)? {
+            None | Some(Bson::Null) => Ok(None),
+            Some(value) => super::flexible_date::from_bson(value)
+                .map(Some)
+                .map_err(de::Error::custom),
+        }
+    }
+}
+
+/// How the bodies sent to clients are written: compact by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct JsonStyle {
+    /// indented over several lines, to be read in the function logs
+    pub pretty: bool,
+}
+
+/// Serializes a response body in the given style.
+pub fn to_api_json<T: Serialize>(body: &T, style: JsonStyle) -> Result<String, serde_json::Error> {
+    if style.pretty {
+        serde_json::to_string_pretty(body)
+    } else {
+        serde_json::to_string(body)
+    }
+}
+
+// USERS
+/// Identifier of the user owning the logs, kept apart from the other strings
+/// so that it can't be swapped with them by mistake.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct UserId(String);
+
+impl UserId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for UserId {
+    fn from(user_id: String) -> UserId {
+        UserId(user_id)
+    }
+}
+
+impl From<&str> for UserId {
+    fn from(user_id: &str) -> UserId {
+        UserId(user_id.to_owned())
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+impl From<UserId> for Bson {
+    fn from(user_id: UserId) -> Bson {
+        Bson::String(user_id.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExperienceLevel {
+    Beginner,
+    Intermediate,
+    Advanced,
+}
+
+/// Preferences of a user, one document per user in the prefs collection.
+/// Fields this struct doesn't know are left alone.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserPrefs {
+    pub user_id: UserId,
+    #[serde(default)]
+    pub experience_level: Option<ExperienceLevel>,
+}
+
 // REQUESTS
 /// Requests come into the runtime as unicode
 /// strings in json format, which can map to any structure that implements `serde::Deserialize`
 /// The runtime pays no attention to the contents of the request payload.
+/// Requests and responses name their fields in camelCase, the stored documents keep
+/// the snake_case names of `Log`.
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ObservationRequest {
-    pub user_id: String,
+    // imported entries take the user of the whole import
+    #[serde(default)]
+    pub user_id: UserId,
     pub object_name: String,
     pub object_location: String,
     pub equipment: String,
     pub eyepiece: String,
     pub notes: String,
+    pub duration_minutes: Option<i64>,
+    pub constellation: Option<String>,
+    #[serde(default)]
+    pub favorite: bool,
+    pub right_ascension: Option<f64>,
+    pub declination: Option<f64>,
+    pub site_latitude: Option<f64>,
+    pub site_longitude: Option<f64>,
+    pub altitude_deg: Option<f64>,
+    pub seeing: Option<u8>,
+    pub estimated_magnitude: Option<f64>,
+    #[serde(default)]
+    pub comparison_stars: Vec<String>,
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub status: ObservationStatus,
+    pub bortle: Option<u8>,
+    pub cloud_cover_pct: Option<f64>,
+    pub limiting_magnitude: Option<f64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// the client or tool the log comes from, `DEFAULT_SOURCE` when not given
+    pub source: Option<String>,
+    /// keys of the sketches and photos already uploaded for the log
+    #[serde(default)]
+    pub attachments: Vec<String>,
+}
+
+/// Source of the logs whose request didn't tell one.
+pub const DEFAULT_SOURCE: &str = "api";
+
+/// The fields a strict request may have, a field added to `ObservationRequest` goes here too.
+const OBSERVATION_REQUEST_FIELDS: [&str; 25] = [
+    "userId",
+    "objectName",
+    "objectLocation",
+    "equipment",
+    "eyepiece",
+    "notes",
+    "durationMinutes",
+    "constellation",
+    "favorite",
+    "rightAscension",
+    "declination",
+    "siteLatitude",
+    "siteLongitude",
+    "altitudeDeg",
+    "seeing",
+    "estimatedMagnitude",
+    "comparisonStars",
+    "rating",
+    "status",
+    "bortle",
+    "cloudCoverPct",
+    "limitingMagnitude",
+    "tags",
+    "source",
+    "attachments",
+];
+
+impl ObservationRequest {
+    /// Parses a request payload. In strict mode fields the request doesn't know are
+    /// rejected, instead of being ignored, so that a typo can't blank a field.
+    pub fn from_json(payload: serde_json::Value, strict: bool) -> Result<Self, FieldError> {
+        if let (true, Some(fields)) = (strict, payload.as_object()) {
+            let unknown = fields
+                .keys()
+                .find(|field| !OBSERVATION_REQUEST_FIELDS.contains(&field.as_str()));
+            if let Some(field) = unknown {
+                return Err(FieldError::new(field, "unknown field"));
+            }
+        }
+        serde_json::from_value(payload).map_err(|e| FieldError::new("payload", &e.to_string()))
+    }
+
+    /// Checks the fields a log can't be saved without and the coordinate ranges.
+    /// Every invalid field is reported, so that the client can fix them all at once.
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        if self.user_id.as_str().trim().is_empty() {
+            errors.push(FieldError::new("userId", "must not be empty"));
+        }
+        errors.extend(self.observation_errors());
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn observation_errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.object_name.trim().is_empty() {
+            errors.push(FieldError::new("objectName", "must not be empty"));
+        }
+        if self.duration_minutes.is_some_and(|minutes| minutes < 0) {
+            errors.push(FieldError::new("durationMinutes", "must not be negative"));
+        }
+        for (field, value, min, max) in self.ranges() {
+            if value.is_some_and(|value| !(min..=max).contains(&value)) {
+                let message = format!("must be between {} and {}", min, max);
+                errors.push(FieldError::new(field, &message));
+            }
+        }
+        errors
+    }
+
+    // the bounded fields, in the order they are checked, shared with the schema
+    fn ranges(&self) -> [(&'static str, Option<f64>, f64, f64); 11] {
+        [
+            ("seeing", self.seeing.map(f64::from), 1.0, 5.0),
+            ("rating", self.rating.map(f64::from), 1.0, 5.0),
+            ("bortle", self.bortle.map(f64::from), 1.0, 9.0),
+            ("cloudCoverPct", self.cloud_cover_pct, 0.0, 100.0),
+            ("limitingMagnitude", self.limiting_magnitude, 0.0, 8.0),
+            ("estimatedMagnitude", self.estimated_magnitude, -2.0, 20.0),
+            ("rightAscension", self.right_ascension, 0.0, 360.0),
+            ("declination", self.declination, -90.0, 90.0),
+            ("siteLatitude", self.site_latitude, -90.0, 90.0),
+            ("siteLongitude", self.site_longitude, -180.0, 180.0),
+            ("altitudeDeg", self.altitude_deg, -90.0, 90.0),
+        ]
+    }
+}
+
+/// Lists the fields of an observation request, with their json type, whether they are
+/// required and their range. Names, types and required fields are all found from the
+/// struct itself, so the schema can't drift from it.
+pub fn observation_schema() -> serde_json::Value {
+    // every optional field given, so that its type shows
+    let sample = ObservationRequest {
+        duration_minutes: Some(0),
+        constellation: Some(String::new()),
+        source: Some(String::new()),
+        right_ascension: Some(0.0),
+        declination: Some(0.0),
+        site_latitude: Some(0.0),
+        site_longitude: Some(0.0),
+        altitude_deg: Some(0.0),
+        seeing: Some(1),
+        estimated_magnitude: Some(0.0),
+        rating: Some(1),
+        bortle: Some(1),
+        cloud_cover_pct: Some(0.0),
+        limiting_magnitude: Some(0.0),
+        ..Default::default()
+    };
+    let sample = serde_json::to_value(sample).unwrap_or_default();
+    let Some(sample) = sample.as_object() else {
+        return serde_json::Value::Null;
+    };
+    let ranges = ObservationRequest::default().ranges();
+    let fields: Vec<serde_json::Value> = sample
+        .iter()
+        .map(|(name, value)| {
+            let json_type = match value {
+                serde_json::Value::String(_) => "string",
+                serde_json::Value::Bool(_) => "boolean",
+                serde_json::Value::Array(_) => "array",
+                serde_json::Value::Number(number) if number.is_f64() => "number",
+                serde_json::Value::Number(_) => "integer",
+                _ => "object",
+            };
+            // a field is required when the request can't be parsed without it
+            let mut without = sample.clone();
+            without.remove(name);
+            let required = serde_json::from_value::<ObservationRequest>(without.into()).is_err();
+            let mut field = serde_json::json!({
+                "name": name,
+                "type": json_type,
+                "required": required,
+            });
+            if let Some((_, _, min, max)) = ranges.iter().find(|range| range.0 == name) {
+                field["min"] = serde_json::json!(min);
+                field["max"] = serde_json::json!(max);
+            }
+            field
+        })
+        .collect();
+    serde_json::json!({ "fields": fields })
+}
+
+// Selects the logs a bulk operation applies to, every condition given must match
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagFilter {
+    pub object_name: Option<String>,
+    pub constellation: Option<String>,
+    pub equipment: Option<String>,
+}
+
+impl TagFilter {
+    fn to_document(&self, user_id: &UserId) -> Document {
+        let mut filter = doc! {"user_id": user_id, "deleted_at": Bson::Null};
+        if let Some(object_name) = self.object_name.as_deref() {
+            filter.insert("observation.object_name", object_name);
+        }
+        if let Some(constellation) = self.constellation.as_deref() {
+            let constellation = canonical_constellation(constellation).unwrap_or(constellation);
+            filter.insert("observation.constellation", constellation);
+        }
+        if let Some(equipment) = self.equipment.as_deref() {
+            filter.insert("observation.equipment", equipment);
+        }
+        filter
+    }
+}
+
+// Bulk import of observations exported from another tool, every entry is saved for `user_id`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportRequest {
+    pub user_id: UserId,
+    pub logs: Vec<ObservationRequest>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct GetLogRequest {
     log_id: ObjectId,
-    user_id: String,
+    user_id: UserId,
+    #[serde(default, with = "flexible_date_option")]
+    pub if_modified_since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// indents the response body
+    #[serde(default)]
+    pub pretty: bool,
+    /// finds the log even when soft deleted, to offer undoing the delete
+    #[serde(default)]
+    pub include_deleted: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Order of the listed logs. Object names are compared ignoring case, so that "m1"
+/// comes before "M31".
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    /// latest observation first
+    #[default]
+    DateDesc,
+    ObjectNameAsc,
+}
+
+impl SortOrder {
+    // the id breaks ties, so that pages don't overlap
+    fn to_document(self) -> Document {
+        match self {
+            SortOrder::DateDesc => doc! {"date": -1, "_id": -1},
+            SortOrder::ObjectNameAsc => doc! {"observation.object_name": 1, "_id": 1},
+        }
+    }
+
+    // strength 2 compares letters and accents but not case
+    fn collation(self) -> Option<Collation> {
+        match self {
+            SortOrder::DateDesc => None,
+            SortOrder::ObjectNameAsc => Some(
+                Collation::builder()
+                    .locale("en")
+                    .strength(CollationStrength::Secondary)
+                    .build(),
+            ),
+        }
+    }
+}
+
+/// How the list handler returns the logs: as json or as an iCalendar, for calendar apps.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListFormat {
+    #[default]
+    Json,
+    Ical,
+}
+
+/// How the get handler returns the log: the whole log as json or
+/// just its notes rendered as html.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    Html,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct GetListRequest {
-    user_id: String,
+    user_id: UserId,
+    constellation: Option<String>,
+    eyepiece: Option<String>,
+    skip: Option<u64>,
+    limit: Option<i64>,
+    #[serde(default)]
+    favorites_only: bool,
+    max_seeing: Option<u8>,
+    min_limiting_magnitude: Option<f64>,
+    source: Option<String>,
+    has_attachments: Option<bool>,
+    /// asks for the report of the logs of this observing night instead of the listing
+    pub night: Option<NaiveDate>,
+    /// asks for the ids of the listed logs only, for clients syncing their copy
+    #[serde(default)]
+    pub ids_only: bool,
+    /// echoes the parsed request in the response, to diagnose clients
+    #[serde(default)]
+    pub debug: bool,
+    /// indents the response body
+    #[serde(default)]
+    pub pretty: bool,
+    #[serde(default)]
+    pub format: ListFormat,
+    #[serde(default)]
+    sort: SortOrder,
+    status: Option<ObservationStatus>,
+    #[serde(default, with = "flexible_date_option")]
+    from: Option<DateTime<Utc>>,
+    #[serde(default, with = "flexible_date_option")]
+    to: Option<DateTime<Utc>>,
+}
+
+impl GetListRequest {
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct DeleteLogRequest {
     log_id: ObjectId,
-    user_id: String,
+    user_id: UserId,
 }
 
-// LOG AND COMPONENTS
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct Observation {
+/// The new content of one of the user's logs, with only the fields the user edits: the
+/// share token, the deletion, the source and the attachments stay the stored ones.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplaceLogRequest {
+    pub log_id: ObjectId,
+    pub user_id: UserId,
+    #[serde(with = "flexible_date")]
+    pub date: DateTime<Utc>,
     pub object_name: String,
     pub object_location: String,
     pub equipment: String,
     pub eyepiece: String,
     pub notes: String,
+    pub duration_minutes: Option<i64>,
+    pub constellation: Option<String>,
+    #[serde(default)]
+    pub favorite: bool,
+    pub right_ascension: Option<f64>,
+    pub declination: Option<f64>,
+    pub site_latitude: Option<f64>,
+    pub site_longitude: Option<f64>,
+    pub altitude_deg: Option<f64>,
+    pub seeing: Option<u8>,
+    pub estimated_magnitude: Option<f64>,
+    #[serde(default)]
+    pub comparison_stars: Vec<String>,
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub status: ObservationStatus,
+    pub bortle: Option<u8>,
+    pub cloud_cover_pct: Option<f64>,
+    pub limiting_magnitude: Option<f64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Log {
-    pub _id: Option<ObjectId>,
-    pub user_id: String,
-    #[serde(with = "chrono::serde::ts_seconds")]
-    pub date: DateTime<Utc>,
-    pub observation: Observation,
+impl ReplaceLogRequest {
+    /// The replacing log, with its fields derived like the ones of a new log.
+    pub fn to_log(&self) -> Log {
+        let req = ObservationRequest {
+            user_id: self.user_id.clone(),
+            object_name: self.object_name.clone(),
+            object_location: self.object_location.clone(),
+            equipment: self.equipment.clone(),
+            eyepiece: self.eyepiece.clone(),
+            notes: self.notes.clone(),
+            duration_minutes: self.duration_minutes,
+            constellation: self.constellation.clone(),
+            favorite: self.favorite,
+            right_ascension: self.right_ascension,
+            declination: self.declination,
+            site_latitude: self.site_latitude,
+            site_longitude: self.site_longitude,
+            altitude_deg: self.altitude_deg,
+            seeing: self.seeing,
+            estimated_magnitude: self.estimated_magnitude,
+            comparison_stars: self.comparison_stars.clone(),
+            rating: self.rating,
+            status: self.status,
+            bortle: self.bortle,
+            cloud_cover_pct: self.cloud_cover_pct,
+            limiting_magnitude: self.limiting_magnitude,
+            tags: self.tags.clone(),
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        log._id = Some(self.log_id);
+        log.set_date(self.date);
+        log
+    }
 }
 
-impl Observation {
-    pub fn new(
-        object_name: String,
-        object_location: String,
-        equipment: String,
-        eyepiece: String,
-        notes: String,
-    ) -> Observation {
-        Observation {
-            object_name,
-            object_location,
-            equipment,
-            eyepiece,
-            notes,
-        }
-    }
+// Two logs to compare or measure the distance of, both must belong to `user_id`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogPairRequest {
+    user_id: UserId,
+    first: ObjectId,
+    second: ObjectId,
+}
 
-    pub fn from_request(req: &ObservationRequest) -> Observation {
-        Self::new(
-            req.object_name.to_owned(),
-            req.object_location.to_owned(),
-            req.equipment.to_owned(),
-            req.eyepiece.to_owned(),
-            req.notes.to_owned(),
-        )
-    }
+// The token of a shared log, as found in its link
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareCardRequest {
+    token: String,
 }
 
-impl Log {
-    pub fn new(user_id: &str, observation: &Observation) -> Log {
-        Log {
-            _id: Some(ObjectId::new()),
-            user_id: user_id.to_owned(),
-            date: Utc::now(),
-            observation: observation.clone(),
+// Free text search in object names and notes, optionally within dates
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchLogRequest {
+    user_id: UserId,
+    query: String,
+    #[serde(default, with = "flexible_date_option")]
+    from: Option<DateTime<Utc>>,
+    #[serde(default, with = "flexible_date_option")]
+    to: Option<DateTime<Utc>>,
+}
+
+// Every log of the user goes in the backup, the deleted ones included
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupRequest {
+    pub user_id: UserId,
+}
+
+// A chunk of the csv export, `skip` is the `next` of the previous chunk
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportRequest {
+    user_id: UserId,
+    skip: Option<u64>,
+    limit: Option<i64>,
+}
+
+// RESPONSES
+/// The runtime requires responses to be serialized into json, this shape is the one
+/// expected by the API Gateway proxy integration.
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct ApiResponse<T: Serialize = String> {
+    pub statusCode: i32,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    pub body: T,
+}
+
+impl<T: Serialize> ApiResponse<T> {
+    pub fn new(status_code: i32, body: T) -> ApiResponse<T> {
+        ApiResponse {
+            statusCode: status_code,
+            headers: HashMap::new(),
+            body,
         }
     }
 
-    pub fn from_observation_request(req: &ObservationRequest) -> Log {
-        let observation = Observation::from_request(req);
-        Self::new(&req.user_id, &observation)
+    pub fn with_header(mut self, name: &str, value: &str) -> ApiResponse<T> {
+        self.headers.insert(name.to_owned(), value.to_owned());
+        self
     }
 }
 
-// DATABASE FUNCTIONS
-pub async fn mongodb_connection() -> Result<Client, mongodb::error::Error> {
-    init();
-    let mut client_options = ClientOptions::parse(&CONFIG.database_url).await?;
+/// A response body along with the request it answers, as the server understood it.
+/// Only sent when the request asks for it, so that nothing leaks by default.
+#[derive(Debug, Serialize)]
+pub struct DebugEnvelope<'a, R, T> {
+    pub request: &'a R,
+    pub result: &'a T,
+}
 
-    // Set the server_api field of the client_options object to set the version of the Stable API on the client
-    let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
-    client_options.server_api = Some(server_api);
-    // Get a handle to the cluster
-    let client = Client::with_options(client_options)?;
-    Ok(client)
+/// A slice of a listing, with what the client needs to render pagination controls.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: u64,
+    pub has_more: bool,
 }
 
-pub async fn log_insertion(
-    log: &Log,
-    mongodb_client: &Client,
-) -> Result<Option<ObjectId>, mongodb::error::Error> {
-    let my_coll: Collection<Log> = mongodb_client
-        .database(&CONFIG.database_name)
-        .collection(&CONFIG.database_collection);
-    let res = my_coll.insert_one(log).await?;
-    let mongo_id = match res.inserted_id {
-        Bson::ObjectId(oid) => Some(oid),
-        _ => None,
-    };
-    Ok(mongo_id)
+/// The objects observed during one observing night, see `observing_night`.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NightSummary {
+    pub date: NaiveDate,
+    pub objects: Vec<String>,
 }
 
-pub async fn log_retrieval(
-    mongodb_client: &Client,
-    log_req: &GetLogRequest,
-) -> Result<Option<Log>, mongodb::error::Error> {
-    let my_coll: Collection<Log> = mongodb_client
-        .database(&CONFIG.database_name)
-        .collection(&CONFIG.database_collection);
-    let filter = doc! {"_id": log_req.log_id, "user_id": log_req.user_id.clone()};
-    my_coll.find_one(filter).await
+/// One report for the logs of an observing night. The night is missing when there are
+/// no logs, the objects and equipment are listed once each.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionReport {
+    pub night: Option<NaiveDate>,
+    pub object_count: usize,
+    pub objects: Vec<String>,
+    pub equipment: Vec<String>,
+    pub notes: String,
 }
 
-pub async fn log_replacement(
-    log: &Log,
-    mongodb_client: &Client,
-) -> Result<UpdateResult, mongodb::error::Error> {
-    let my_coll: Collection<Log> = mongodb_client
-        .database(&CONFIG.database_name)
-        .collection(&CONFIG.database_collection);
-    let filter = doc! {"_id": log._id, "user_id": log.user_id.clone()};
-    my_coll.replace_one(filter, log.to_owned()).await
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
 }
 
-pub async fn log_listing(
-    mongodb_client: &Client,
-    list_req: &GetListRequest,
-) -> Result<Cursor<Log>, mongodb::error::Error> {
-    let my_coll: Collection<Log> = mongodb_client
-        .database(&CONFIG.database_name)
-        .collection(&CONFIG.database_collection);
-    let filter = doc! {"user_id": list_req.user_id.clone()};
-    my_coll.find(filter).await
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> FieldError {
+        FieldError {
+            field: field.to_owned(),
+            message: message.to_owned(),
+        }
+    }
 }
 
-pub async fn log_deletion(
-    mongodb_client: &Client,
-    log_req: &DeleteLogRequest,
-) -> Result<DeleteResult, mongodb::error::Error> {
-    let my_coll: Collection<Log> = mongodb_client
-        .database(&CONFIG.database_name)
-        .collection(&CONFIG.database_collection);
-    let filter = doc! {"_id": log_req.log_id, "user_id": log_req.user_id.clone()};
-    my_coll.delete_one(filter).await
+// Result of each import entry, in the same order as the request
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportOutcome {
+    Inserted(ObjectId),
+    Invalid(Vec<FieldError>),
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        log_deletion, log_insertion, log_listing, log_replacement, log_retrieval,
-        mongodb_connection, DeleteLogRequest, GetListRequest, GetLogRequest, Log,
-        ObservationRequest,
-    };
-    use futures::TryStreamExt;
+/// `next` is where the following chunk starts, none on the last one.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CsvChunk {
+    pub csv: String,
+    pub next: Option<u64>,
+}
 
-    const USER_ID: &str = "fake_id";
+/// Enough of an adjacent log to link to it.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Neighbor {
+    pub id: ObjectId,
+    pub object_name: String,
+}
 
-    #[tokio::test]
-    async fn db_connection_test() {
-        let client = mongodb_connection().await;
-        assert!(client.is_ok());
-    }
+/// A log with the ones observed right before and after it, none at either end.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogWithNeighbors {
+    pub log: Log,
+    pub previous: Option<Neighbor>,
+    pub next: Option<Neighbor>,
+}
+
+/// A leaderboard line, the experience level is there when the user set it.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderboardEntry {
+    pub user_id: UserId,
+    pub count: u64,
+    pub experience_level: Option<ExperienceLevel>,
+}
+
+/// An observation field with different values in two observations, as json.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldDiff {
+    pub field: String,
+    pub old: serde_json::Value,
+    pub new: serde_json::Value,
+}
+
+/// The angular distance between the objects of two logs, see `log_separation`.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Separation {
+    pub separation_deg: f64,
+}
+
+/// What the dashboard shows of a user's logs, see `log_dashboard_stats`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DashboardStats {
+    pub total: u64,
+    pub top_object: Option<String>,
+    pub first: Option<DateTime<Utc>>,
+    pub last: Option<DateTime<Utc>>,
+    pub monthly_counts: Vec<(String, u64)>,
+}
+
+/// Documents moved to the new user id, per collection.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameReport {
+    pub logs: u64,
+    pub prefs: u64,
+    pub profiles: u64,
+}
+
+// ERRORS
+#[derive(Debug)]
+pub enum NightlogError {
+    /// no log with the requested id
+    NotFound,
+    /// the log exists but belongs to another user
+    Forbidden,
+    Database(mongodb::error::Error),
+    /// a response body couldn't be turned into json
+    Serialization(serde_json::Error),
+    /// a backup couldn't be written
+    Io(std::io::Error),
+    /// a request field the operation can't go on with
+    Invalid(FieldError),
+    /// the operation took longer than allowed
+    Timeout(std::time::Duration),
+}
+
+impl NightlogError {
+    pub fn status_code(&self) -> i32 {
+        match self {
+            NightlogError::NotFound => 404,
+            NightlogError::Forbidden => 403,
+            NightlogError::Invalid(_) => 400,
+            NightlogError::Timeout(_) => 504,
+            NightlogError::Database(_) | NightlogError::Serialization(_) | NightlogError::Io(_) => {
+                500
+            }
+        }
+    }
+
+    /// Short name of the error, for response bodies and logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NightlogError::NotFound => "not_found",
+            NightlogError::Forbidden => "forbidden",
+            NightlogError::Database(_) => "database",
+            NightlogError::Serialization(_) => "serialization",
+            NightlogError::Io(_) => "io",
+            NightlogError::Invalid(_) => "invalid",
+            NightlogError::Timeout(_) => "timeout",
+        }
+    }
+
+    /// No server could be picked to send the operation to, so nothing was done and it
+    /// can be tried again, writes included.
+    pub fn is_server_selection(&self) -> bool {
+        matches!(
+            self,
+            NightlogError::Database(e) if matches!(e.kind.as_ref(), ErrorKind::ServerSelection { .. })
+        )
+    }
+
+    // the seed list of a `mongodb+srv` url can fail to resolve during a failover too
+    fn is_dns_resolve(&self) -> bool {
+        matches!(
+            self,
+            NightlogError::Database(e) if matches!(e.kind.as_ref(), ErrorKind::DnsResolve { .. })
+        )
+    }
+
+    /// The error as a response, with its status and a body telling its kind.
+    pub fn to_response(&self) -> ApiResponse {
+        let body = serde_json::json!({"error": self.kind(), "message": self.to_string()});
+        ApiResponse::new(self.status_code(), body.to_string())
+    }
+}
+
+impl fmt::Display for NightlogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NightlogError::NotFound => write!(f, "log not found"),
+            NightlogError::Forbidden => write!(f, "log belongs to another user"),
+            NightlogError::Database(e) => write!(f, "database error: {}", e),
+            NightlogError::Serialization(e) => write!(f, "serialization error: {}", e),
+            NightlogError::Io(e) => write!(f, "write error: {}", e),
+            NightlogError::Invalid(e) => write!(f, "invalid {}: {}", e.field, e.message),
+            NightlogError::Timeout(limit) => {
+                write!(f, "operation timed out after {} ms", limit.as_millis())
+            }
+        }
+    }
+}
+
+impl std::error::Error for NightlogError {}
+
+impl From<mongodb::error::Error> for NightlogError {
+    fn from(e: mongodb::error::Error) -> Self {
+        NightlogError::Database(e)
+    }
+}
+
+impl From<serde_json::Error> for NightlogError {
+    fn from(e: serde_json::Error) -> Self {
+        NightlogError::Serialization(e)
+    }
+}
+
+impl From<std::io::Error> for NightlogError {
+    fn from(e: std::io::Error) -> Self {
+        NightlogError::Io(e)
+    }
+}
+
+// LOG AND COMPONENTS
+/// A planned observation is on the wishlist: its date is the one it was planned on
+/// until it gets completed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObservationStatus {
+    Planned,
+    #[default]
+    Completed,
+}
+
+impl ObservationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObservationStatus::Planned => "planned",
+            ObservationStatus::Completed => "completed",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct Observation {
+    pub object_name: String,
+    pub object_location: String,
+    pub equipment: String,
+    pub eyepiece: String,
+    pub notes: String,
+    /// length of the observing session in minutes, when the observer recorded it
+    pub duration_minutes: Option<i64>,
+    /// IAU name of the constellation hosting the object
+    pub constellation: Option<String>,
+    /// equatorial coordinates of the object, both in degrees
+    pub right_ascension: Option<f64>,
+    pub declination: Option<f64>,
+    /// where the observer was, in degrees with longitudes positive east
+    pub site_latitude: Option<f64>,
+    pub site_longitude: Option<f64>,
+    /// height of the object above the horizon, derived from the coordinates and the date
+    /// when the observer didn't give it
+    pub altitude_deg: Option<f64>,
+    /// tells a derived altitude from one the observer gave
+    #[serde(default)]
+    pub altitude_computed: bool,
+    /// Antoniadi scale, from 1 (perfect) to 5 (very bad)
+    pub seeing: Option<u8>,
+    /// visual estimate of a variable star, with the stars it was compared to
+    pub estimated_magnitude: Option<f64>,
+    #[serde(default)]
+    pub comparison_stars: Vec<String>,
+    /// how worth the object was, from 1 to 5
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub status: ObservationStatus,
+    /// sky darkness on the Bortle scale, from 1 (darkest) to 9 (inner city)
+    pub bortle: Option<u8>,
+    /// share of the sky covered by clouds, from 0 to 100
+    pub cloud_cover_pct: Option<f64>,
+    /// faintest star visible to the naked eye, estimated from the conditions when
+    /// the observer didn't give it
+    pub limiting_magnitude: Option<f64>,
+    /// derived from the equipment and eyepiece focal lengths
+    pub magnification: Option<f64>,
+    /// derived from the date, illuminated fraction of the moon from 0 to 1
+    pub moon_illumination: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Log {
+    pub _id: Option<ObjectId>,
+    pub user_id: UserId,
+    #[serde(with = "bson_date")]
+    pub date: DateTime<Utc>,
+    pub observation: Observation,
+    #[serde(default, with = "bson_date_option")]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub favorite: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// lets anyone holding it read the log, see `log_get_by_share`
+    #[serde(default)]
+    pub share_token: Option<String>,
+    /// set by a soft delete, such logs are left out of retrievals, listings and stats
+    #[serde(default, with = "bson_date_option")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// missing on the logs saved before sources were recorded
+    #[serde(default)]
+    pub source: Option<String>,
+    /// keys of the sketches and photos stored with the log
+    #[serde(default)]
+    pub attachments: Vec<String>,
+}
+
+/// The observation of a `PublicLog`, with the field names of the API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicObservation {
+    pub object_name: String,
+    pub object_location: String,
+    pub equipment: String,
+    pub eyepiece: String,
+    pub notes: String,
+    pub duration_minutes: Option<i64>,
+    pub constellation: Option<String>,
+    pub right_ascension: Option<f64>,
+    pub declination: Option<f64>,
+    pub site_latitude: Option<f64>,
+    pub site_longitude: Option<f64>,
+    pub altitude_deg: Option<f64>,
+    pub seeing: Option<u8>,
+    pub estimated_magnitude: Option<f64>,
+    pub comparison_stars: Vec<String>,
+    pub rating: Option<u8>,
+    pub status: ObservationStatus,
+    pub bortle: Option<u8>,
+    pub cloud_cover_pct: Option<f64>,
+    pub limiting_magnitude: Option<f64>,
+    pub magnification: Option<f64>,
+    pub moon_illumination: Option<f64>,
+}
+
+impl From<&Observation> for PublicObservation {
+    fn from(observation: &Observation) -> PublicObservation {
+        PublicObservation {
+            object_name: observation.object_name.clone(),
+            object_location: observation.object_location.clone(),
+            equipment: observation.equipment.clone(),
+            eyepiece: observation.eyepiece.clone(),
+            notes: observation.notes.clone(),
+            duration_minutes: observation.duration_minutes,
+            constellation: observation.constellation.clone(),
+            right_ascension: observation.right_ascension,
+            declination: observation.declination,
+            site_latitude: observation.site_latitude,
+            site_longitude: observation.site_longitude,
+            altitude_deg: observation.altitude_deg,
+            seeing: observation.seeing,
+            estimated_magnitude: observation.estimated_magnitude,
+            comparison_stars: observation.comparison_stars.clone(),
+            rating: observation.rating,
+            status: observation.status,
+            bortle: observation.bortle,
+            cloud_cover_pct: observation.cloud_cover_pct,
+            limiting_magnitude: observation.limiting_magnitude,
+            magnification: observation.magnification,
+            moon_illumination: observation.moon_illumination,
+        }
+    }
+}
+
+/// What clients get to see of a `Log`, without the fields only the server uses.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicLog {
+    #[serde(rename = "_id")]
+    pub _id: Option<ObjectId>,
+    pub user_id: UserId,
+    #[serde(with = "flexible_date")]
+    pub date: DateTime<Utc>,
+    pub observation: PublicObservation,
+    #[serde(with = "flexible_date_option")]
+    pub updated_at: Option<DateTime<Utc>>,
+    pub favorite: bool,
+    pub tags: Vec<String>,
+    pub source: Option<String>,
+    pub attachments: Vec<String>,
+    pub julian_date: f64,
+    /// at the site of the observation, when its longitude was logged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_sidereal_time: Option<f64>,
+}
+
+/// A line of a backup: plain json with the field names of the API, the share token left
+/// out and the deletion kept, as the deleted logs are backed up too.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupLog {
+    /// the hex string of the id
+    #[serde(rename = "_id")]
+    pub _id: Option<String>,
+    pub user_id: UserId,
+    #[serde(with = "flexible_date")]
+    pub date: DateTime<Utc>,
+    pub observation: PublicObservation,
+    #[serde(with = "flexible_date_option")]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(with = "flexible_date_option")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub favorite: bool,
+    pub tags: Vec<String>,
+    pub source: Option<String>,
+    pub attachments: Vec<String>,
+}
+
+/// The little a shared link shows of a log: notes are cut to `SHARE_PREVIEW_LENGTH`
+/// characters on a single line.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareCard {
+    pub object_name: String,
+    /// the day of the observation, as `%Y-%m-%d`
+    pub date: String,
+    pub summary: String,
+}
+
+pub const SHARE_PREVIEW_LENGTH: usize = 140;
+
+/// Records which observation fields a replacement changed.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AuditEntry {
+    pub _id: Option<ObjectId>,
+    pub log_id: ObjectId,
+    pub user_id: UserId,
+    pub changed_fields: Vec<String>,
+    #[serde(with = "bson_date")]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Observation {
+    pub fn new(
+        object_name: String,
+        object_location: String,
+        equipment: String,
+        eyepiece: String,
+        notes: String,
+    ) -> Observation {
+        Observation {
+            object_name,
+            object_location,
+            equipment,
+            eyepiece,
+            notes,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_request(req: &ObservationRequest) -> Observation {
+        let mut observation = Self::new(
+            req.object_name.to_owned(),
+            req.object_location.to_owned(),
+            req.equipment.to_owned(),
+            req.eyepiece.to_owned(),
+            req.notes.to_owned(),
+        );
+        observation.duration_minutes = req.duration_minutes;
+        observation.right_ascension = req.right_ascension;
+        observation.declination = req.declination;
+        observation.site_latitude = req.site_latitude;
+        observation.site_longitude = req.site_longitude;
+        observation.altitude_deg = req.altitude_deg;
+        observation.seeing = req.seeing;
+        observation.estimated_magnitude = req.estimated_magnitude;
+        observation.comparison_stars = req.comparison_stars.clone();
+        observation.rating = req.rating;
+        observation.status = req.status;
+        observation.bortle = req.bortle;
+        observation.cloud_cover_pct = req.cloud_cover_pct;
+        observation.limiting_magnitude = req.limiting_magnitude.or_else(|| {
+            let (bortle, cloud_cover_pct) = (req.bortle?, req.cloud_cover_pct?);
+            Some(estimate_limiting_magnitude(bortle, cloud_cover_pct))
+        });
+        observation.constellation = req
+            .constellation
+            .as_deref()
+            .map(|name| canonical_constellation(name).unwrap_or(name).to_owned());
+        observation
+    }
+
+    /// Names of the fields whose value differs in `other`.
+    pub fn changed_fields(&self, other: &Observation) -> Vec<String> {
+        let (Ok(old), Ok(new)) = (
+            mongodb::bson::to_document(self),
+            mongodb::bson::to_document(other),
+        ) else {
+            return Vec::new();
+        };
+        old.iter()
+            .filter(|(field, value)| new.get(field.as_str()) != Some(*value))
+            .map(|(field, _)| field.to_owned())
+            .collect()
+    }
+}
+
+/// The fields that differ from `a` to `b`, in the order of the observation struct and
+/// named like in the API.
+pub fn diff_observations(a: &Observation, b: &Observation) -> Vec<FieldDiff> {
+    let (old, new) = (PublicObservation::from(a), PublicObservation::from(b));
+    let (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+    old.into_iter()
+        .filter_map(|(field, old)| {
+            let new = new.get(&field).cloned().unwrap_or_default();
+            (old != new).then_some(FieldDiff { field, old, new })
+        })
+        .collect()
+}
+
+// NOTES
+/// Renders markdown notes to html, dropping anything that could run in the browser.
+pub fn render_notes_html(notes: &str) -> String {
+    let parser = pulldown_cmark::Parser::new(notes);
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, parser);
+    ammonia::clean(&html)
+}
+
+// CSV
+const CSV_HEADER: &str = "id,date,object_name,object_location,equipment,eyepiece,notes,constellation,duration_minutes,right_ascension,declination,seeing";
+
+// fields with separators, quotes or line breaks are quoted, doubling the inner quotes
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// One row per log, missing values are left empty.
+pub fn logs_to_csv(logs: &[Log], header: bool) -> String {
+    fn optional<T: ToString>(value: Option<T>) -> String {
+        value.map(|value| value.to_string()).unwrap_or_default()
+    }
+    let mut csv = String::new();
+    if header {
+        csv.push_str(CSV_HEADER);
+        csv.push('\n');
+    }
+    for log in logs {
+        let observation = &log.observation;
+        let row = [
+            optional(log._id.map(|id| id.to_hex())),
+            log.date.to_rfc3339(),
+            csv_field(&observation.object_name),
+            csv_field(&observation.object_location),
+            csv_field(&observation.equipment),
+            csv_field(&observation.eyepiece),
+            csv_field(&observation.notes),
+            csv_field(observation.constellation.as_deref().unwrap_or_default()),
+            optional(observation.duration_minutes),
+            optional(observation.right_ascension),
+            optional(observation.declination),
+            optional(observation.seeing),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+// ICALENDAR
+const ICAL_DATE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+// backslashes, separators and line breaks are escaped as RFC 5545 wants for text values
+fn ical_text(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+// lines longer than 75 octets go on as continuation lines starting with a space
+fn ical_line(calendar: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            calendar.push_str("\r\n ");
+            length = 1;
+        }
+        calendar.push(c);
+        length += c.len_utf8();
+    }
+    calendar.push_str("\r\n");
+}
+
+/// A calendar with one event per log, at the observation date and lasting its duration
+/// when there is one. The object is the summary and the notes the description.
+pub fn logs_to_ical(logs: &[Log]) -> String {
+    let mut calendar = String::new();
+    for line in ["BEGIN:VCALENDAR", "VERSION:2.0", "PRODID:-//NightLog//EN"] {
+        ical_line(&mut calendar, line);
+    }
+    for log in logs {
+        let observation = &log.observation;
+        let stamp = log.updated_at.unwrap_or(log.date);
+        ical_line(&mut calendar, "BEGIN:VEVENT");
+        if let Some(id) = log._id {
+            ical_line(&mut calendar, &format!("UID:{}@nightlog", id.to_hex()));
+        }
+        ical_line(
+            &mut calendar,
+            &format!("DTSTAMP:{}", stamp.format(ICAL_DATE_FORMAT)),
+        );
+        ical_line(
+            &mut calendar,
+            &format!("DTSTART:{}", log.date.format(ICAL_DATE_FORMAT)),
+        );
+        if let Some(minutes) = observation.duration_minutes {
+            ical_line(&mut calendar, &format!("DURATION:PT{}M", minutes));
+        }
+        ical_line(
+            &mut calendar,
+            &format!("SUMMARY:{}", ical_text(&observation.object_name)),
+        );
+        if !observation.notes.is_empty() {
+            ical_line(
+                &mut calendar,
+                &format!("DESCRIPTION:{}", ical_text(&observation.notes)),
+            );
+        }
+        ical_line(&mut calendar, "END:VEVENT");
+    }
+    ical_line(&mut calendar, "END:VCALENDAR");
+    calendar
+}
+
+// TELESCOPE CONTROL
+// sexagesimal with rounded seconds, `unit_seconds` seconds in a unit
+fn sexagesimal(value: f64, unit_seconds: f64) -> (i64, i64, i64) {
+    let seconds = (value * unit_seconds).round() as i64;
+    (seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Target list for GoTo mounts, one `name,RA,Dec` line per log with coordinates, the
+/// right ascension in hours as `hh:mm:ss` and the declination as `±dd:mm:ss`.
+/// Logs without coordinates are skipped.
+pub fn log_to_nexstar(logs: &[Log]) -> String {
+    let mut targets = String::from("# name,ra,dec\n");
+    for log in logs {
+        let observation = &log.observation;
+        let (Some(right_ascension), Some(declination)) =
+            (observation.right_ascension, observation.declination)
+        else {
+            continue;
+        };
+        let (hours, minutes, seconds) =
+            sexagesimal(right_ascension.rem_euclid(360.0) / 15.0, 3600.0);
+        let sign = if declination < 0.0 { '-' } else { '+' };
+        let (degrees, arcminutes, arcseconds) = sexagesimal(declination.abs(), 3600.0);
+        targets.push_str(&format!(
+            "{},{:02}:{:02}:{:02},{}{:02}:{:02}:{:02}\n",
+            observation.object_name.replace(',', " "),
+            hours % 24,
+            minutes,
+            seconds,
+            sign,
+            degrees,
+            arcminutes,
+            arcseconds
+        ));
+    }
+    targets
+}
+
+// OPTICS
+// The telescope focal length is the one after the aperture, as in "Dobson 254/1250",
+// and the eyepiece is written as "25mm".
+pub fn magnification(equipment: &str, eyepiece: &str) -> Option<f64> {
+    fn leading_number(text: &str) -> Option<f64> {
+        let text = text.trim();
+        let end = text
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(text.len());
+        text[..end].parse().ok()
+    }
+    let (_, focal_length) = equipment.rsplit_once('/')?;
+    let telescope = leading_number(focal_length)?;
+    let eyepiece = leading_number(eyepiece)?;
+    (eyepiece > 0.0).then(|| telescope / eyepiece)
+}
+
+// CONSTELLATIONS
+pub const IAU_CONSTELLATIONS: [&str; 88] = [
+    "Andromeda",
+    "Antlia",
+    "Apus",
+    "Aquarius",
+    "Aquila",
+    "Ara",
+    "Aries",
+    "Auriga",
+    "Bootes",
+    "Caelum",
+    "Camelopardalis",
+    "Cancer",
+    "Canes Venatici",
+    "Canis Major",
+    "Canis Minor",
+    "Capricornus",
+    "Carina",
+    "Cassiopeia",
+    "Centaurus",
+    "Cepheus",
+    "Cetus",
+    "Chamaeleon",
+    "Circinus",
+    "Columba",
+    "Coma Berenices",
+    "Corona Australis",
+    "Corona Borealis",
+    "Corvus",
+    "Crater",
+    "Crux",
+    "Cygnus",
+    "Delphinus",
+    "Dorado",
+    "Draco",
+    "Equuleus",
+    "Eridanus",
+    "Fornax",
+    "Gemini",
+    "Grus",
+    "Hercules",
+    "Horologium",
+    "Hydra",
+    "Hydrus",
+    "Indus",
+    "Lacerta",
+    "Leo",
+    "Leo Minor",
+    "Lepus",
+    "Libra",
+    "Lupus",
+    "Lynx",
+    "Lyra",
+    "Mensa",
+    "Microscopium",
+    "Monoceros",
+    "Musca",
+    "Norma",
+    "Octans",
+    "Ophiuchus",
+    "Orion",
+    "Pavo",
+    "Pegasus",
+    "Perseus",
+    "Phoenix",
+    "Pictor",
+    "Pisces",
+    "Piscis Austrinus",
+    "Puppis",
+    "Pyxis",
+    "Reticulum",
+    "Sagitta",
+    "Sagittarius",
+    "Scorpius",
+    "Sculptor",
+    "Scutum",
+    "Serpens",
+    "Sextans",
+    "Taurus",
+    "Telescopium",
+    "Triangulum",
+    "Triangulum Australe",
+    "Tucana",
+    "Ursa Major",
+    "Ursa Minor",
+    "Vela",
+    "Virgo",
+    "Volans",
+    "Vulpecula",
+];
+
+/// Maps a constellation name, whatever its case, to the canonical IAU spelling.
+pub fn canonical_constellation(name: &str) -> Option<&'static str> {
+    let name = name.trim();
+    IAU_CONSTELLATIONS
+        .iter()
+        .find(|canonical| canonical.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+impl Log {
+    pub fn new(user_id: &UserId, observation: &Observation) -> Log {
+        let now = Utc::now();
+        let mut log = Log {
+            _id: Some(ObjectId::new()),
+            user_id: user_id.clone(),
+            date: now,
+            observation: observation.clone(),
+            updated_at: Some(now),
+            favorite: false,
+            tags: Vec::new(),
+            share_token: None,
+            deleted_at: None,
+            source: None,
+            attachments: Vec::new(),
+        };
+        log.derive_fields();
+        log
+    }
+
+    pub fn to_public(&self) -> PublicLog {
+        PublicLog {
+            _id: self._id,
+            user_id: self.user_id.clone(),
+            date: self.date,
+            observation: PublicObservation::from(&self.observation),
+            updated_at: self.updated_at,
+            favorite: self.favorite,
+            tags: self.tags.clone(),
+            source: self.source.clone(),
+            attachments: self.attachments.clone(),
+            julian_date: julian_date(self.date),
+            local_sidereal_time: self
+                .observation
+                .site_longitude
+                .map(|longitude| local_sidereal_time(self.date, longitude)),
+        }
+    }
+
+    pub fn to_backup(&self) -> BackupLog {
+        BackupLog {
+            _id: self._id.map(|id| id.to_hex()),
+            user_id: self.user_id.clone(),
+            date: self.date,
+            observation: PublicObservation::from(&self.observation),
+            updated_at: self.updated_at,
+            deleted_at: self.deleted_at,
+            favorite: self.favorite,
+            tags: self.tags.clone(),
+            source: self.source.clone(),
+            attachments: self.attachments.clone(),
+        }
+    }
+
+    pub fn to_share_card(&self) -> ShareCard {
+        let notes = self
+            .observation
+            .notes
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut summary: String = notes.chars().take(SHARE_PREVIEW_LENGTH).collect();
+        if summary.len() < notes.len() {
+            summary.push('…');
+        }
+        ShareCard {
+            object_name: self.observation.object_name.clone(),
+            date: self.date.format("%Y-%m-%d").to_string(),
+            summary,
+        }
+    }
+
+    /// Recomputes the observation fields that only depend on the rest of the log.
+    pub fn derive_fields(&mut self) {
+        let observation = &mut self.observation;
+        observation.constellation = observation
+            .constellation
+            .as_deref()
+            .map(|name| canonical_constellation(name).unwrap_or(name).to_owned());
+        observation.magnification = magnification(&observation.equipment, &observation.eyepiece);
+        observation.moon_illumination = Some(moon_illumination(&self.date));
+        self.fill_altitude();
+    }
+
+    /// Moves the log to another date, with the fields derived from it.
+    pub fn set_date(&mut self, date: DateTime<Utc>) {
+        self.clear_computed_altitude();
+        self.date = date;
+        self.derive_fields();
+    }
+
+    // An altitude computed for other inputs wasn't given by the observer, it goes for
+    // `fill_altitude` to compute again. Logs saved before `altitude_computed` have theirs
+    // recognized when it still matches the stored inputs.
+    fn clear_computed_altitude(&mut self) {
+        let altitude = self.observation.altitude_deg;
+        if self.observation.altitude_computed
+            || (altitude.is_some() && altitude == self.computed_altitude())
+        {
+            self.observation.altitude_deg = None;
+            self.observation.altitude_computed = false;
+        }
+    }
+
+    pub fn from_observation_request(req: &ObservationRequest) -> Log {
+        let observation = Observation::from_request(req);
+        let mut log = Self::new(&req.user_id, &observation);
+        log.favorite = req.favorite;
+        log.tags = req.tags.clone();
+        log.attachments = req.attachments.clone();
+        let source = req.source.as_deref().unwrap_or(DEFAULT_SOURCE);
+        log.source = Some(source.to_owned());
+        log
+    }
+
+    /// Fills the altitude of the object from its coordinates, the site and the date,
+    /// when they are all known and the observer didn't give one.
+    pub fn fill_altitude(&mut self) {
+        if self.observation.altitude_deg.is_none() {
+            self.observation.altitude_deg = self.computed_altitude();
+            self.observation.altitude_computed = self.observation.altitude_deg.is_some();
+        }
+    }
+
+    fn computed_altitude(&self) -> Option<f64> {
+        let observation = &self.observation;
+        let (ra, dec) = (observation.right_ascension?, observation.declination?);
+        let (site_lat, site_lon) = (observation.site_latitude?, observation.site_longitude?);
+        Some(compute_altitude(ra, dec, site_lat, site_lon, self.date))
+    }
+
+    /// A log dated after the current time, as a wrong client clock makes them.
+    pub fn is_future_dated(&self) -> bool {
+        self.date > Utc::now()
+    }
+
+    /// Checks the date of a log about to be saved, future dates are only refused
+    /// with `reject_future`.
+    pub fn check_date(&self, reject_future: bool) -> Result<(), FieldError> {
+        if reject_future && self.is_future_dated() {
+            return Err(FieldError::new("date", "must not be in the future"));
+        }
+        Ok(())
+    }
+
+    /// Tells whether the log changed after `since`.
+    /// Logs saved before `updated_at` was tracked are always considered modified.
+    pub fn modified_since(&self, since: &DateTime<Utc>) -> bool {
+        match self.updated_at {
+            Some(updated_at) => updated_at > *since,
+            None => true,
+        }
+    }
+}
+
+// CATALOG
+/// A catalog object with its J2000 position, in degrees like the logs.
+#[derive(Debug, PartialEq)]
+pub struct CatalogObject {
+    pub name: &'static str,
+    pub constellation: &'static str,
+    pub right_ascension: f64,
+    pub declination: f64,
+}
+
+const fn catalog_object(
+    name: &'static str,
+    constellation: &'static str,
+    right_ascension: f64,
+    declination: f64,
+) -> CatalogObject {
+    CatalogObject {
+        name,
+        constellation,
+        right_ascension,
+        declination,
+    }
+}
+
+// the most observed Messier objects, enough to catch the common mistakes
+pub const CATALOG: [CatalogObject; 14] = [
+    catalog_object("M1", "Taurus", 83.633, 22.015),
+    catalog_object("M8", "Sagittarius", 270.904, -24.387),
+    catalog_object("M13", "Hercules", 250.423, 36.461),
+    catalog_object("M27", "Vulpecula", 299.901, 22.721),
+    catalog_object("M31", "Andromeda", 10.685, 41.269),
+    catalog_object("M33", "Triangulum", 23.462, 30.660),
+    catalog_object("M42", "Orion", 83.822, -5.391),
+    catalog_object("M44", "Cancer", 130.100, 19.667),
+    catalog_object("M45", "Taurus", 56.750, 24.117),
+    catalog_object("M51", "Canes Venatici", 202.470, 47.195),
+    catalog_object("M57", "Lyra", 283.396, 33.029),
+    catalog_object("M81", "Ursa Major", 148.888, 69.065),
+    catalog_object("M101", "Ursa Major", 210.802, 54.349),
+    catalog_object("M104", "Virgo", 189.998, -11.623),
+];
+
+/// Finds a catalog object whatever the case and spacing of the name, as in "m 31".
+pub fn find_catalog_object(name: &str) -> Option<&'static CatalogObject> {
+    let name: String = name.split_whitespace().collect();
+    CATALOG
+        .iter()
+        .find(|object| object.name.eq_ignore_ascii_case(&name))
+}
+
+// other designations of the catalog objects, so that statistics don't split an object
+pub const ALIASES: [(&str, &str); 18] = [
+    ("NGC 1952", "M1"),
+    ("Crab Nebula", "M1"),
+    ("NGC 6523", "M8"),
+    ("NGC 6205", "M13"),
+    ("NGC 6853", "M27"),
+    ("NGC 224", "M31"),
+    ("Andromeda Galaxy", "M31"),
+    ("NGC 598", "M33"),
+    ("NGC 1976", "M42"),
+    ("Orion Nebula", "M42"),
+    ("NGC 2632", "M44"),
+    ("Pleiades", "M45"),
+    ("NGC 5194", "M51"),
+    ("NGC 6720", "M57"),
+    ("Ring Nebula", "M57"),
+    ("NGC 3031", "M81"),
+    ("NGC 5457", "M101"),
+    ("NGC 4594", "M104"),
+];
+
+// case and spacing don't tell designations apart, as in `find_catalog_object`
+fn alias_key(name: &str) -> String {
+    name.split_whitespace().collect::<String>().to_uppercase()
+}
+
+/// The canonical designation of an object, as "M31" for "NGC 224". Names that aren't
+/// known are returned as they are.
+pub fn resolve_alias(name: &str) -> String {
+    let key = alias_key(name);
+    ALIASES
+        .iter()
+        .find(|(alias, _)| alias_key(alias) == key)
+        .map(|(_, canonical)| canonical.to_string())
+        .or_else(|| find_catalog_object(name).map(|object| object.name.to_owned()))
+        .unwrap_or_else(|| name.to_owned())
+}
+
+// The same mapping as `resolve_alias`, for grouping inside an aggregation. Only spaces
+// are dropped from the field, which is what the logged names contain.
+fn alias_expression(field: &str) -> Document {
+    let key = doc! {"$toUpper": {"$replaceAll": {"input": field, "find": " ", "replacement": ""}}};
+    let branches: Vec<Document> = CATALOG
+        .iter()
+        .map(|object| {
+            let keys: Vec<String> = ALIASES
+                .iter()
+                .filter(|(_, canonical)| *canonical == object.name)
+                .map(|(alias, _)| alias_key(alias))
+                .chain([alias_key(object.name)])
+                .collect();
+            doc! {"case": {"$in": [key.clone(), keys]}, "then": object.name}
+        })
+        .collect();
+    doc! {"$switch": {"branches": branches, "default": field}}
+}
+
+// NIGHTS
+/// An observing night runs from noon to noon, so that logs taken before and after
+/// midnight belong to the same session.
+pub fn observing_night(date: &DateTime<Utc>) -> NaiveDate {
+    (*date - Duration::hours(12)).date_naive()
+}
+
+/// Counts the consecutive nights ending with the most recent one.
+pub fn current_streak(nights: &[NaiveDate]) -> u32 {
+    let mut nights = nights.to_vec();
+    nights.sort_unstable();
+    nights.dedup();
+    let mut streak = 0;
+    let mut expected = None;
+    for night in nights.iter().rev() {
+        if expected.is_some_and(|expected| expected != *night) {
+            break;
+        }
+        streak += 1;
+        expected = night.pred_opt();
+    }
+    streak
+}
+
+/// Consecutive dates further apart than `max_gap`, the dates going in ascending order.
+pub fn date_gaps(
+    dates: &[DateTime<Utc>],
+    max_gap: Duration,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut dates = dates.to_vec();
+    dates.sort_unstable();
+    dates
+        .windows(2)
+        .filter(|pair| pair[1] - pair[0] > max_gap)
+        .map(|pair| (pair[0], pair[1]))
+        .collect()
+}
+
+/// Objects and equipment go in the order they were first used, the notes of each log
+/// are prefixed with its object.
+pub fn session_report(logs: &[Log]) -> SessionReport {
+    let mut logs: Vec<&Log> = logs.iter().collect();
+    logs.sort_by_key(|log| log.date);
+    let mut objects: Vec<String> = Vec::new();
+    let mut equipment: Vec<String> = Vec::new();
+    let mut notes = Vec::new();
+    for log in &logs {
+        let observation = &log.observation;
+        if !objects.contains(&observation.object_name) {
+            objects.push(observation.object_name.clone());
+        }
+        if !observation.equipment.is_empty() && !equipment.contains(&observation.equipment) {
+            equipment.push(observation.equipment.clone());
+        }
+        if !observation.notes.trim().is_empty() {
+            notes.push(format!(
+                "{}: {}",
+                observation.object_name,
+                observation.notes.trim()
+            ));
+        }
+    }
+    SessionReport {
+        night: logs.first().map(|log| observing_night(&log.date)),
+        object_count: objects.len(),
+        objects,
+        equipment,
+        notes: notes.join(NOTES_SEPARATOR),
+    }
+}
+
+// ASTRONOMY
+// Angles are in degrees, right ascension included, and longitudes are positive east.
+
+/// Days, with their fraction, since noon of 4713 BC January 1 in the Julian calendar.
+/// UTC is taken for the uniform time scale, which is off by about a minute.
+pub fn julian_date(date: DateTime<Utc>) -> f64 {
+    date.timestamp_millis() as f64 / 86_400_000.0 + 2_440_587.5
+}
+
+const SYNODIC_MONTH: f64 = 29.530_588_853;
+// julian date of the new moon of 2000-01-06 18:14 UTC
+const REFERENCE_NEW_MOON: f64 = 2_451_550.26;
+
+/// Illuminated fraction of the moon, from the mean synodic month. It ignores the
+/// orbit eccentricity, which is good enough to tell the phase apart.
+pub fn moon_illumination(date: &DateTime<Utc>) -> f64 {
+    let age = (julian_date(*date) - REFERENCE_NEW_MOON).rem_euclid(SYNODIC_MONTH);
+    (1.0 - (2.0 * std::f64::consts::PI * age / SYNODIC_MONTH).cos()) / 2.0
+}
+
+/// Moon phases by illuminated fraction, each bucket up to its bound excluded.
+pub const MOON_PHASE_BUCKETS: [(&str, f64); 5] = [
+    ("new", 0.05),
+    ("crescent", 0.35),
+    ("quarter", 0.65),
+    ("gibbous", 0.95),
+    ("full", f64::INFINITY),
+];
+
+/// Great circle distance in degrees between two positions given in degrees, with the
+/// haversine formula that stays accurate for close objects such as double stars.
+pub fn angular_separation(ra1: f64, dec1: f64, ra2: f64, dec2: f64) -> f64 {
+    let (dec1, dec2) = (dec1.to_radians(), dec2.to_radians());
+    let half_dec = (dec2 - dec1) / 2.0;
+    let half_ra = (ra2 - ra1).to_radians() / 2.0;
+    let haversine = half_dec.sin().powi(2) + dec1.cos() * dec2.cos() * half_ra.sin().powi(2);
+    (2.0 * haversine.sqrt().clamp(0.0, 1.0).asin()).to_degrees()
+}
+
+/// Naked-eye limiting magnitude from the middle of each class range of Bortle's
+/// description of the scale (7.6-8.0 for class 1 down to 4.1-4.5 for class 8, 4.0 for
+/// class 9), losing up to two magnitudes as clouds cover the sky and brighten it.
+pub fn estimate_limiting_magnitude(bortle: u8, cloud_cover_pct: f64) -> f64 {
+    let clear_sky = match bortle.clamp(1, 9) {
+        9 => 4.0,
+        bortle => 8.3 - 0.5 * f64::from(bortle),
+    };
+    clear_sky - 2.0 * cloud_cover_pct.clamp(0.0, 100.0) / 100.0
+}
+
+// Greenwich mean sidereal time in degrees (Meeus, Astronomical Algorithms, 12.4)
+fn greenwich_sidereal_time(date: &DateTime<Utc>) -> f64 {
+    let days = julian_date(*date) - 2_451_545.0;
+    let centuries = days / 36_525.0;
+    let gmst = 280.460_618_37 + 360.985_647_366_29 * days + 0.000_387_933 * centuries.powi(2)
+        - centuries.powi(3) / 38_710_000.0;
+    gmst.rem_euclid(360.0)
+}
+
+/// Local mean sidereal time in degrees, the right ascension crossing the meridian of
+/// the given longitude.
+pub fn local_sidereal_time(date: DateTime<Utc>, longitude: f64) -> f64 {
+    (greenwich_sidereal_time(&date) + longitude).rem_euclid(360.0)
+}
+
+/// Altitude in degrees of an object above the geometric horizon of the site at the
+/// given time, without refraction.
+pub fn compute_altitude(ra: f64, dec: f64, site_lat: f64, site_lon: f64, at: DateTime<Utc>) -> f64 {
+    let hour_angle = (greenwich_sidereal_time(&at) + site_lon - ra).to_radians();
+    let (dec, site_lat) = (dec.to_radians(), site_lat.to_radians());
+    let sin_altitude = site_lat.sin() * dec.sin() + site_lat.cos() * dec.cos() * hour_angle.cos();
+    sin_altitude.clamp(-1.0, 1.0).asin().to_degrees()
+}
+
+/// Tells whether an object was above the geometric horizon of the site at the given time.
+pub fn is_above_horizon(
+    ra: f64,
+    dec: f64,
+    site_lat: f64,
+    site_lon: f64,
+    at: DateTime<Utc>,
+) -> bool {
+    compute_altitude(ra, dec, site_lat, site_lon, at) > 0.0
+}
+
+// DATABASE FUNCTIONS
+// Parsing a `mongodb+srv` url resolves the seed list through DNS, so it's done once
+// per execution environment and the options are shared by every connection.
+static CLIENT_OPTIONS: OnceCell<ClientOptions> = OnceCell::const_new();
+
+// times the url got parsed, for the tests to tell the options are really shared
+#[cfg(test)]
+static CLIENT_OPTIONS_PARSES: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(0);
+
+fn configure_client_options(
+    client_options: &mut ClientOptions,
+    app_name: &str,
+    server_selection_timeout: std::time::Duration,
+) {
+    // Set the server_api field of the client_options object to set the version of the Stable API on the client
+    let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
+    client_options.server_api = Some(server_api);
+    // Atlas attributes the connections to the app by this name
+    client_options.app_name = Some(app_name.to_string());
+    // a failover has to show as a failed server selection before the operation times
+    // out, for `with_retry` to try it again; a timeout given in the url is kept
+    client_options
+        .server_selection_timeout
+        .get_or_insert(server_selection_timeout);
+}
+
+async fn client_options() -> Result<ClientOptions, mongodb::error::Error> {
+    let client_options = CLIENT_OPTIONS
+        .get_or_try_init(|| async {
+            #[cfg(test)]
+            CLIENT_OPTIONS_PARSES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let mut client_options = ClientOptions::parse(&CONFIG.database_url).await?;
+            // every attempt of `with_retry` fits in a single operation timeout
+            let server_selection_timeout = CONFIG.operation_timeout / (FAILOVER_ATTEMPTS + 1);
+            configure_client_options(
+                &mut client_options,
+                &CONFIG.app_name,
+                server_selection_timeout,
+            );
+            Ok::<_, mongodb::error::Error>(client_options)
+        })
+        .await?;
+    Ok(client_options.clone())
+}
+
+pub async fn mongodb_connection() -> Result<Client, mongodb::error::Error> {
+    init();
+    let client_options = client_options().await?;
+    // Get a handle to the cluster
+    let client = Client::with_options(client_options)?;
+    Ok(client)
+}
+
+/// `mongodb_connection` tried again like the first operation of a handler, as the
+/// lookup of the servers can fail during a failover as well.
+pub async fn connect_with_retry() -> Result<Client, NightlogError> {
+    with_retry(mongodb_connection).await
+}
+
+// During a failover the first operation of a cold function can find no server for a
+// few seconds
+const FAILOVER_ATTEMPTS: u32 = 3;
+const FAILOVER_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Runs `operation` until a server takes it, at most `FAILOVER_ATTEMPTS` times, waiting
+/// a bit longer after each failure. Any other error is returned at once.
+pub async fn with_retry<T, E, F, Fut>(mut operation: F) -> Result<T, NightlogError>
+where
+    F: FnMut() -> Fut,
+    Fut: IntoFuture<Output = Result<T, E>>,
+    NightlogError: From<E>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await.map_err(NightlogError::from) {
+            Err(e)
+                if (e.is_server_selection() || e.is_dns_resolve())
+                    && attempt < FAILOVER_ATTEMPTS =>
+            {
+                tracing::warn!("attempt {} found no server: {}", attempt, e);
+                tokio::time::sleep(FAILOVER_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Bounds an operation to `limit`, so that a slow query fails with `Timeout` instead
+/// of using up the whole function timeout.
+pub async fn with_timeout<T, E, Fut>(
+    limit: std::time::Duration,
+    operation: Fut,
+) -> Result<T, NightlogError>
+where
+    Fut: IntoFuture<Output = Result<T, E>>,
+    NightlogError: From<E>,
+{
+    match tokio::time::timeout(limit, operation).await {
+        Ok(res) => res.map_err(NightlogError::from),
+        Err(_) => Err(NightlogError::Timeout(limit)),
+    }
+}
+
+// INDEXES
+const USER_DATE_INDEX: &str = "user_id_date";
+
+// listings are always scoped to a user and ordered by date
+fn user_date_index_keys() -> Document {
+    doc! {"user_id": 1, "date": -1}
+}
+
+pub async fn ensure_indexes(mongodb_client: &Client) -> Result<(), mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let options = IndexOptions::builder()
+        .name(USER_DATE_INDEX.to_string())
+        .build();
+    let index = IndexModel::builder()
+        .keys(user_date_index_keys())
+        .options(options)
+        .build();
+    my_coll.create_index(index).await?;
+    Ok(())
+}
+
+/// Checks that the collection has the indexes created by `ensure_indexes`.
+pub async fn verify_indexes(mongodb_client: &Client) -> Result<bool, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let indexes = my_coll
+        .list_indexes()
+        .await?
+        .try_collect::<Vec<IndexModel>>()
+        .await?;
+    let expected = user_date_index_keys();
+    Ok(indexes
+        .iter()
+        .any(|index| same_index_keys(&index.keys, &expected)))
+}
+
+// the server may return key directions with a different numeric type
+fn same_index_keys(keys: &Document, expected: &Document) -> bool {
+    keys.len() == expected.len()
+        && keys.iter().zip(expected.iter()).all(
+            |((key, direction), (expected_key, expected_direction))| {
+                key == expected_key
+                    && bson_to_i64(Some(direction)) == bson_to_i64(Some(expected_direction))
+            },
+        )
+}
+
+pub async fn log_insertion(
+    log: &Log,
+    mongodb_client: &Client,
+) -> Result<Option<ObjectId>, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let res = with_timeout(CONFIG.operation_timeout, my_coll.insert_one(log)).await?;
+    let mongo_id = match res.inserted_id {
+        Bson::ObjectId(oid) => Some(oid),
+        _ => None,
+    };
+    Ok(mongo_id)
+}
+
+// Invalid entries are reported and skipped, the valid ones go in with a single insert
+pub async fn log_import(
+    mongodb_client: &Client,
+    req: &ImportRequest,
+) -> Result<Vec<ImportOutcome>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let mut outcomes: Vec<Option<ImportOutcome>> = Vec::with_capacity(req.logs.len());
+    let mut positions = Vec::new();
+    let mut logs = Vec::new();
+    for entry in &req.logs {
+        let mut errors = entry.observation_errors();
+        if req.user_id.as_str().trim().is_empty() {
+            errors.insert(0, FieldError::new("userId", "must not be empty"));
+        }
+        if !errors.is_empty() {
+            outcomes.push(Some(ImportOutcome::Invalid(errors)));
+            continue;
+        }
+        let mut log = Log::from_observation_request(entry);
+        log.user_id = req.user_id.clone();
+        positions.push(outcomes.len());
+        logs.push(log);
+        outcomes.push(None);
+    }
+    if !logs.is_empty() {
+        let res = my_coll.insert_many(&logs).await?;
+        for (index, id) in res.inserted_ids {
+            if let (Some(&position), Bson::ObjectId(oid)) = (positions.get(index), id) {
+                outcomes[position] = Some(ImportOutcome::Inserted(oid));
+            }
+        }
+    }
+    Ok(outcomes.into_iter().flatten().collect())
+}
+
+pub async fn log_retrieval(
+    mongodb_client: &Client,
+    log_req: &GetLogRequest,
+) -> Result<Option<Log>, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let mut filter = doc! {"_id": log_req.log_id, "user_id": log_req.user_id.clone()};
+    if !log_req.include_deleted {
+        filter.insert("deleted_at", Bson::Null);
+    }
+    with_timeout(CONFIG.operation_timeout, my_coll.find_one(filter)).await
+}
+
+// Logs come back in the order of `ids`, the ones missing or owned by someone else are left out
+pub async fn log_retrieval_many(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    ids: &[ObjectId],
+) -> Result<Vec<Log>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {"_id": {"$in": ids}, "user_id": user_id, "deleted_at": Bson::Null};
+    let mut logs: Vec<Log> = my_coll.find(filter).await?.try_collect().await?;
+    logs.sort_by_key(|log| ids.iter().position(|id| Some(*id) == log._id));
+    Ok(logs)
+}
+
+// Either log missing, or belonging to someone else, is reported as not found. The same
+// id twice gives the same log twice.
+async fn log_pair(
+    mongodb_client: &Client,
+    pair_req: &LogPairRequest,
+) -> Result<(Log, Log), NightlogError> {
+    let ids = [pair_req.first, pair_req.second];
+    let mut logs = log_retrieval_many(mongodb_client, &pair_req.user_id, &ids).await?;
+    if pair_req.first == pair_req.second && logs.len() == 1 {
+        logs.push(logs[0].clone());
+    }
+    let Ok([first, second]) = <[Log; 2]>::try_from(logs) else {
+        return Err(NightlogError::NotFound);
+    };
+    Ok((first, second))
+}
+
+pub async fn log_diff(
+    mongodb_client: &Client,
+    diff_req: &LogPairRequest,
+) -> Result<Vec<FieldDiff>, NightlogError> {
+    let (first, second) = log_pair(mongodb_client, diff_req).await?;
+    Ok(diff_observations(&first.observation, &second.observation))
+}
+
+/// Angular separation in degrees of the objects of two logs. Logs without both
+/// coordinates can't be measured and are refused as invalid.
+pub async fn log_separation(
+    mongodb_client: &Client,
+    separation_req: &LogPairRequest,
+) -> Result<f64, NightlogError> {
+    let (first, second) = log_pair(mongodb_client, separation_req).await?;
+    let coordinates = |log: &Log| {
+        let observation = &log.observation;
+        observation.right_ascension.zip(observation.declination)
+    };
+    let (Some((ra1, dec1)), Some((ra2, dec2))) = (coordinates(&first), coordinates(&second)) else {
+        let message = "both logs must have a right ascension and a declination";
+        return Err(NightlogError::Invalid(FieldError::new(
+            "coordinates",
+            message,
+        )));
+    };
+    Ok(angular_separation(ra1, dec1, ra2, dec2))
+}
+
+pub async fn log_replacement(
+    log: &Log,
+    mongodb_client: &Client,
+) -> Result<UpdateResult, NightlogError> {
+    replace_log(log, mongodb_client, CONFIG.features.audit).await
+}
+
+// Each statement is bounded on its own, as cancelling a whole replacement could stop it
+// between two of them. A timeout on the audit entry still leaves the replacement done
+// without its entry.
+async fn replace_log(
+    log: &Log,
+    mongodb_client: &Client,
+    audit: bool,
+) -> Result<UpdateResult, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    // the id is looked up regardless of the user, to tell a missing log from someone else's
+    let lookup = my_coll.find_one(doc! {"_id": log._id});
+    let Some(previous) = with_timeout(CONFIG.operation_timeout, lookup).await? else {
+        return Err(NightlogError::NotFound);
+    };
+    // the owner never changes: a replacement with another `user_id` is refused here,
+    // instead of matching nothing in the filter below
+    if previous.user_id != log.user_id {
+        return Err(NightlogError::Forbidden);
+    }
+    let filter = doc! {"_id": log._id, "user_id": log.user_id.clone()};
+    let mut log = log.to_owned();
+    // the fields only the server sets are kept, whatever the replacement holds
+    log.share_token = previous.share_token.clone();
+    log.deleted_at = previous.deleted_at;
+    log.source = previous.source.clone();
+    log.attachments = previous.attachments.clone();
+    // an altitude sent back as it was computed is still the computed one
+    if previous.observation.altitude_computed
+        && log.observation.altitude_deg == previous.observation.altitude_deg
+    {
+        log.observation.altitude_deg = None;
+    }
+    log.updated_at = Some(Utc::now());
+    log.derive_fields();
+    let res = with_timeout(CONFIG.operation_timeout, my_coll.replace_one(filter, &log)).await?;
+    if let Some(log_id) = log._id {
+        if audit && res.modified_count > 0 {
+            let audit_coll: Collection<AuditEntry> = mongodb_client
+                .database(&CONFIG.database_name)
+                .collection(&CONFIG.audit_collection);
+            let entry = AuditEntry {
+                _id: None,
+                log_id,
+                user_id: log.user_id.clone(),
+                changed_fields: previous.observation.changed_fields(&log.observation),
+                timestamp: log.updated_at.unwrap_or_else(Utc::now),
+            };
+            with_timeout(CONFIG.operation_timeout, audit_coll.insert_one(entry)).await?;
+        }
+    }
+    Ok(res)
+}
+
+// Only logs whose derived fields are stale get written, so an interrupted run can
+// simply be started again.
+pub async fn recompute_derived(
+    mongodb_client: &Client,
+    user_id: Option<&UserId>,
+) -> Result<u64, mongodb::error::Error> {
+    let filter = match user_id {
+        Some(user_id) => doc! {"user_id": user_id},
+        None => Document::new(),
+    };
+    recompute_derived_matching(mongodb_client, filter).await
+}
+
+async fn recompute_derived_matching(
+    mongodb_client: &Client,
+    filter: Document,
+) -> Result<u64, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let mut cursor = my_coll.find(filter).await?;
+    let mut updated = 0;
+    while let Some(mut log) = cursor.try_next().await? {
+        let stored = log.observation.clone();
+        log.clear_computed_altitude();
+        log.derive_fields();
+        let derived = &log.observation;
+        if (
+            &stored.constellation,
+            stored.magnification,
+            stored.moon_illumination,
+            stored.altitude_deg,
+            stored.altitude_computed,
+        ) == (
+            &derived.constellation,
+            derived.magnification,
+            derived.moon_illumination,
+            derived.altitude_deg,
+            derived.altitude_computed,
+        ) {
+            continue;
+        }
+        let update = doc! {"$set": {
+            "observation.constellation": derived.constellation.as_deref(),
+            "observation.magnification": derived.magnification,
+            "observation.moon_illumination": derived.moon_illumination,
+            "observation.altitude_deg": derived.altitude_deg,
+            "observation.altitude_computed": derived.altitude_computed,
+        }};
+        let res = my_coll.update_one(doc! {"_id": log._id}, update).await?;
+        updated += res.modified_count;
+    }
+    Ok(updated)
+}
+
+// Only the logs saved before the moon illumination was derived are touched, so running it
+// again changes nothing. Unlike `recompute_derived` the other derived fields are left alone.
+pub async fn backfill_moon_phase(
+    mongodb_client: &Client,
+    user_id: Option<&UserId>,
+) -> Result<u64, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let mut filter = doc! {"observation.moon_illumination": Bson::Null};
+    if let Some(user_id) = user_id {
+        filter.insert("user_id", user_id);
+    }
+    let mut cursor = my_coll.find(filter).await?;
+    let mut updated = 0;
+    while let Some(log) = cursor.try_next().await? {
+        let update = doc! {"$set": {
+            "observation.moon_illumination": moon_illumination(&log.date),
+        }};
+        let res = my_coll.update_one(doc! {"_id": log._id}, update).await?;
+        updated += res.modified_count;
+    }
+    Ok(updated)
+}
+
+// Logs are copied before being deleted, and only the copied ids are deleted: transactions
+// need a replica set, this way a failure leaves a log in both collections but never loses it.
+// The copy replaces the one a failed run left, so that archiving can simply be run again.
+pub async fn archive_old_logs(
+    mongodb_client: &Client,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, mongodb::error::Error> {
+    let database = mongodb_client.database(&CONFIG.database_name);
+    let my_coll: Collection<Log> = database.collection(&CONFIG.database_collection);
+    let archive_coll: Collection<Log> = database.collection(&CONFIG.archive_collection);
+    let old_logs: Vec<Log> = my_coll
+        .find(doc! {"date": {"$lt": date_bson(&cutoff)}})
+        .await?
+        .try_collect()
+        .await?;
+    if old_logs.is_empty() {
+        return Ok(0);
+    }
+    let mut ids = Vec::new();
+    for log in old_logs {
+        let Some(log_id) = log._id else {
+            continue;
+        };
+        archive_coll
+            .replace_one(doc! {"_id": log_id}, &log)
+            .upsert(true)
+            .await?;
+        ids.push(log_id);
+    }
+    let res = my_coll.delete_many(doc! {"_id": {"$in": ids}}).await?;
+    Ok(res.deleted_count)
+}
+
+// Dates used to be stored as unix timestamps (seconds), the update and delete times as
+// well. The conversion runs on the server and only touches the integer dates, so it can
+// simply be run again after a failure. Each converted field is counted.
+pub async fn migrate_integer_dates(mongodb_client: &Client) -> Result<u64, mongodb::error::Error> {
+    let database = mongodb_client.database(&CONFIG.database_name);
+    let mut converted = 0;
+    for collection in [&CONFIG.database_collection, &CONFIG.archive_collection] {
+        let my_coll: Collection<Document> = database.collection(collection);
+        for field in ["date", "updated_at", "deleted_at"] {
+            let filter = doc! {field: {"$type": ["int", "long"]}};
+            let seconds = format!("${}", field);
+            let update = vec![
+                doc! {"$set": {field: {"$toDate": {"$multiply": [{"$toLong": seconds}, 1000_i64]}}}},
+            ];
+            let res = my_coll.update_many(filter, update).await?;
+            converted += res.modified_count;
+        }
+    }
+    Ok(converted)
+}
+
+// Documents are read raw, so that one that doesn't fit `Log` any more is reported instead
+// of failing the whole scan. Documents without an object id can't be reported and are skipped.
+pub async fn scan_invalid_documents(
+    mongodb_client: &Client,
+) -> Result<Vec<ObjectId>, mongodb::error::Error> {
+    let my_coll: Collection<Document> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let mut cursor = my_coll.find(Document::new()).await?;
+    let mut invalid = Vec::new();
+    while let Some(document) = cursor.try_next().await? {
+        let Ok(id) = document.get_object_id("_id") else {
+            continue;
+        };
+        if let Err(e) = mongodb::bson::from_document::<Log>(document) {
+            tracing::warn!("log {} doesn't deserialize: {}", id, e);
+            invalid.push(id);
+        }
+    }
+    Ok(invalid)
+}
+
+// Preferences and equipment profiles are keyed by user like the logs. Like the archive this
+// runs without a transaction, a failed rename can simply be run again.
+pub async fn rename_user(
+    mongodb_client: &Client,
+    old_id: &UserId,
+    new_id: &UserId,
+) -> Result<RenameReport, mongodb::error::Error> {
+    let database = mongodb_client.database(&CONFIG.database_name);
+    let filter = doc! {"user_id": old_id};
+    let update = doc! {"$set": {"user_id": new_id}};
+    let mut report = RenameReport::default();
+    for (collection, modified) in [
+        (&CONFIG.database_collection, &mut report.logs),
+        (&CONFIG.prefs_collection, &mut report.prefs),
+        (&CONFIG.profiles_collection, &mut report.profiles),
+    ] {
+        let res = database
+            .collection::<Document>(collection)
+            .update_many(filter.clone(), update.clone())
+            .await?;
+        *modified = res.modified_count;
+    }
+    Ok(report)
+}
+
+// The magnification depends on the equipment, so the logs with the new name get their
+// derived fields recomputed afterwards
+pub async fn rename_equipment(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    from: &str,
+    to: &str,
+) -> Result<u64, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {"user_id": user_id, "observation.equipment": from};
+    let update = doc! {"$set": {
+        "observation.equipment": to,
+        "updated_at": date_bson(&Utc::now()),
+    }};
+    let res = my_coll.update_many(filter, update).await?;
+    if res.modified_count > 0 {
+        let renamed = doc! {"user_id": user_id, "observation.equipment": to};
+        recompute_derived_matching(mongodb_client, renamed).await?;
+    }
+    Ok(res.modified_count)
+}
+
+const SHARE_TOKEN_LENGTH: usize = 32;
+
+// a new share replaces the previous token, so sharing again revokes the old link; soft
+// deleted logs are not found
+pub async fn log_create_share(
+    mongodb_client: &Client,
+    log_id: ObjectId,
+    user_id: &UserId,
+) -> Result<String, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let token: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(SHARE_TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    let res = my_coll
+        .update_one(
+            doc! {"_id": log_id, "user_id": user_id, "deleted_at": Bson::Null},
+            doc! {"$set": {"share_token": &token}},
+        )
+        .await?;
+    if res.matched_count == 0 {
+        return Err(NightlogError::NotFound);
+    }
+    Ok(token)
+}
+
+// not scoped to a user, the token is the only credential; soft deleted logs stop
+// being shared, and are again once undeleted
+pub async fn log_get_by_share(
+    mongodb_client: &Client,
+    token: &str,
+) -> Result<Option<Log>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    my_coll
+        .find_one(doc! {"share_token": token, "deleted_at": Bson::Null})
+        .await
+}
+
+/// The share card of the log a token was created for.
+pub async fn log_share_card(
+    mongodb_client: &Client,
+    share_req: &ShareCardRequest,
+) -> Result<ShareCard, NightlogError> {
+    match log_get_by_share(mongodb_client, &share_req.token).await? {
+        Some(log) => Ok(log.to_share_card()),
+        None => Err(NightlogError::NotFound),
+    }
+}
+
+// logs on the same date are ordered by id, like in listings
+pub async fn log_with_neighbors(
+    mongodb_client: &Client,
+    log_id: ObjectId,
+    user_id: &UserId,
+) -> Result<LogWithNeighbors, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {"_id": log_id, "user_id": user_id, "deleted_at": Bson::Null};
+    let Some(log) = my_coll.find_one(filter).await? else {
+        return Err(NightlogError::NotFound);
+    };
+    let date = date_bson(&log.date);
+    let mut neighbors = Vec::with_capacity(2);
+    for (operator, direction) in [("$lt", -1), ("$gt", 1)] {
+        let filter = doc! {
+            "user_id": user_id,
+            "deleted_at": Bson::Null,
+            "$or": [
+                {"date": {operator: date.clone()}},
+                {"date": date.clone(), "_id": {operator: log_id}},
+            ],
+        };
+        let neighbor = my_coll
+            .find_one(filter)
+            .sort(doc! {"date": direction, "_id": direction})
+            .await?;
+        neighbors.push(neighbor.and_then(|neighbor| {
+            Some(Neighbor {
+                id: neighbor._id?,
+                object_name: neighbor.observation.object_name,
+            })
+        }));
+    }
+    let next = neighbors.pop().flatten();
+    let previous = neighbors.pop().flatten();
+    Ok(LogWithNeighbors {
+        log,
+        previous,
+        next,
+    })
+}
+
+// `$addToSet` leaves logs already carrying the tag untouched
+pub async fn log_add_tag_bulk(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    filter: TagFilter,
+    tag: String,
+) -> Result<u64, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let res = my_coll
+        .update_many(
+            filter.to_document(user_id),
+            doc! {"$addToSet": {"tags": tag}},
+        )
+        .await?;
+    Ok(res.modified_count)
+}
+
+// entries recorded within the same second keep their insertion order
+pub async fn log_history(
+    mongodb_client: &Client,
+    log_id: ObjectId,
+    user_id: &UserId,
+) -> Result<Vec<AuditEntry>, mongodb::error::Error> {
+    let audit_coll: Collection<AuditEntry> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.audit_collection);
+    let options = FindOptions::builder()
+        .sort(doc! {"timestamp": 1, "_id": 1})
+        .build();
+    audit_coll
+        .find(doc! {"log_id": log_id, "user_id": user_id})
+        .with_options(options)
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn log_listing(
+    mongodb_client: &Client,
+    list_req: &GetListRequest,
+) -> Result<Cursor<Log>, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = build_list_filter(list_req);
+    let options = FindOptions::builder()
+        .sort(list_req.sort.to_document())
+        .collation(list_req.sort.collation())
+        .skip(list_req.skip)
+        .limit(list_limit(list_req))
+        .build();
+    let find = my_coll.find(filter).with_options(options);
+    with_timeout(CONFIG.operation_timeout, find).await
+}
+
+// The cursor is drained within the limit too, the query alone is only half of the time
+async fn log_listing_collected(
+    mongodb_client: &Client,
+    list_req: &GetListRequest,
+) -> Result<Vec<Log>, NightlogError> {
+    let listing = async {
+        let cursor = log_listing(mongodb_client, list_req).await?;
+        Ok::<_, NightlogError>(cursor.try_collect::<Vec<Log>>().await?)
+    };
+    with_timeout(CONFIG.operation_timeout, listing).await
+}
+
+// Same logs and order as `log_listing`, but only their ids come back from the server
+pub async fn log_listing_ids(
+    mongodb_client: &Client,
+    list_req: &GetListRequest,
+) -> Result<Vec<ObjectId>, mongodb::error::Error> {
+    let my_coll: Collection<Document> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let options = FindOptions::builder()
+        .projection(doc! {"_id": 1})
+        .sort(list_req.sort.to_document())
+        .collation(list_req.sort.collation())
+        .skip(list_req.skip)
+        .limit(list_limit(list_req))
+        .build();
+    let logs: Vec<Document> = my_coll
+        .find(build_list_filter(list_req))
+        .with_options(options)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(logs
+        .iter()
+        .filter_map(|log| log.get_object_id("_id").ok())
+        .collect())
+}
+
+/// Upper bound to the logs returned by a single listing, whatever the client asks for.
+pub const MAX_LIST_RESULTS: i64 = 1000;
+
+fn list_limit(list_req: &GetListRequest) -> i64 {
+    capped_limit(list_req.limit)
+}
+
+fn capped_limit(limit: Option<i64>) -> i64 {
+    limit
+        .filter(|limit| (1..MAX_LIST_RESULTS).contains(limit))
+        .unwrap_or(MAX_LIST_RESULTS)
+}
+
+/// A listing that also tells whether `MAX_LIST_RESULTS` cut it short.
+#[derive(Debug)]
+pub struct CappedListing {
+    pub logs: Vec<Log>,
+    pub capped: bool,
+}
+
+pub async fn log_listing_capped(
+    mongodb_client: &Client,
+    list_req: &GetListRequest,
+) -> Result<CappedListing, NightlogError> {
+    let logs = log_listing_collected(mongodb_client, list_req).await?;
+    // counting is only needed when the cap could have been hit
+    let mut capped = false;
+    if logs.len() as i64 == MAX_LIST_RESULTS && list_limit(list_req) == MAX_LIST_RESULTS {
+        let my_coll: Collection<Log> = mongodb_client
+            .database(&CONFIG.database_name)
+            .collection(&CONFIG.database_collection);
+        let count = my_coll.count_documents(build_list_filter(list_req));
+        let total = with_timeout(CONFIG.operation_timeout, count).await?;
+        capped = list_req.skip.unwrap_or(0) + (logs.len() as u64) < total;
+    }
+    Ok(CappedListing { logs, capped })
+}
+
+// One json log per line, in the shape of `BackupLog`, written as the cursor goes so that
+// the logs are never all in memory. The number of logs written is returned.
+pub async fn backup_user_logs(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<u64, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let options = FindOptions::builder()
+        .sort(doc! {"date": 1, "_id": 1})
+        .build();
+    let mut cursor = my_coll
+        .find(doc! {"user_id": user_id})
+        .with_options(options)
+        .await?;
+    let mut written = 0;
+    while let Some(log) = cursor.try_next().await? {
+        let mut line = serde_json::to_vec(&log.to_backup())?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+        written += 1;
+    }
+    writer.flush().await?;
+    Ok(written)
+}
+
+// The header only goes on the first chunk, asking one log more than the limit tells
+// whether there is a following chunk.
+pub async fn log_export_csv(
+    mongodb_client: &Client,
+    export_req: &ExportRequest,
+) -> Result<CsvChunk, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let skip = export_req.skip.unwrap_or(0);
+    let limit = capped_limit(export_req.limit);
+    let options = FindOptions::builder()
+        .sort(doc! {"date": -1, "_id": -1})
+        .skip(skip)
+        .limit(limit + 1)
+        .build();
+    let mut logs: Vec<Log> = my_coll
+        .find(doc! {"user_id": export_req.user_id.clone(), "deleted_at": Bson::Null})
+        .with_options(options)
+        .await?
+        .try_collect()
+        .await?;
+    let next = if logs.len() as i64 > limit {
+        logs.truncate(limit as usize);
+        Some(skip + limit as u64)
+    } else {
+        None
+    };
+    Ok(CsvChunk {
+        csv: logs_to_csv(&logs, skip == 0),
+        next,
+    })
+}
+
+pub async fn log_list_page(
+    mongodb_client: &Client,
+    list_req: &GetListRequest,
+) -> Result<Page<Log>, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let count = my_coll.count_documents(build_list_filter(list_req));
+    let total = with_timeout(CONFIG.operation_timeout, count).await?;
+    let items = log_listing_collected(mongodb_client, list_req).await?;
+    let has_more = list_req.skip.unwrap_or(0) + (items.len() as u64) < total;
+    Ok(Page {
+        items,
+        total,
+        has_more,
+    })
+}
+
+/// The query a listing runs, built apart from the database call so that each
+/// combination of conditions can be checked on its own.
+pub fn build_list_filter(list_req: &GetListRequest) -> Document {
+    // a missing `deleted_at` matches null as well
+    let mut filter = doc! {"user_id": list_req.user_id.clone(), "deleted_at": Bson::Null};
+    if let Some(constellation) = list_req.constellation.as_deref() {
+        let constellation = canonical_constellation(constellation).unwrap_or(constellation);
+        filter.insert("observation.constellation", constellation);
+    }
+    if let Some(eyepiece) = list_req.eyepiece.as_deref() {
+        filter.insert("observation.eyepiece", eyepiece);
+    }
+    if list_req.favorites_only {
+        filter.insert("favorite", true);
+    }
+    if let Some(source) = list_req.source.as_deref() {
+        filter.insert("source", source);
+    }
+    // a first element only exists in a non empty array
+    if let Some(has_attachments) = list_req.has_attachments {
+        filter.insert("attachments.0", doc! {"$exists": has_attachments});
+    }
+    // logs without a seeing value never match a comparison
+    if let Some(max_seeing) = list_req.max_seeing {
+        filter.insert("observation.seeing", doc! {"$lte": i32::from(max_seeing)});
+    }
+    if let Some(min_limiting_magnitude) = list_req.min_limiting_magnitude {
+        filter.insert(
+            "observation.limiting_magnitude",
+            doc! {"$gte": min_limiting_magnitude},
+        );
+    }
+    // logs saved before the status existed are completed ones
+    match list_req.status {
+        Some(ObservationStatus::Planned) => {
+            filter.insert("observation.status", ObservationStatus::Planned.as_str());
+        }
+        Some(ObservationStatus::Completed) => {
+            let planned = ObservationStatus::Planned.as_str();
+            filter.insert("observation.status", doc! {"$ne": planned});
+        }
+        None => {}
+    }
+    if let Some(date_range) = date_range(list_req.from, list_req.to) {
+        filter.insert("date", date_range);
+    }
+    filter
+}
+
+// both bounds are inclusive and go in the same condition on `date`
+fn date_range(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Option<Document> {
+    let mut date_range = Document::new();
+    if let Some(from) = from {
+        date_range.insert("$gte", date_bson(&from));
+    }
+    if let Some(to) = to {
+        date_range.insert("$lte", date_bson(&to));
+    }
+    (!date_range.is_empty()).then_some(date_range)
+}
+
+// the query is matched literally, whatever characters it contains
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn build_search_filter(search_req: &SearchLogRequest) -> Document {
+    let pattern = Regex {
+        pattern: escape_regex(search_req.query.trim()),
+        options: "i".to_string(),
+    };
+    let mut filter = doc! {
+        "user_id": search_req.user_id.clone(),
+        "deleted_at": Bson::Null,
+        "$or": [
+            {"observation.object_name": pattern.clone()},
+            {"observation.notes": pattern},
+        ],
+    };
+    if let Some(date_range) = date_range(search_req.from, search_req.to) {
+        filter.insert("date", date_range);
+    }
+    filter
+}
+
+pub async fn log_search(
+    mongodb_client: &Client,
+    search_req: &SearchLogRequest,
+) -> Result<Vec<Log>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let options = FindOptions::builder()
+        .sort(doc! {"date": -1, "_id": -1})
+        .limit(MAX_LIST_RESULTS)
+        .build();
+    my_coll
+        .find(build_search_filter(search_req))
+        .with_options(options)
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn log_deletion(
+    mongodb_client: &Client,
+    log_req: &DeleteLogRequest,
+) -> Result<DeleteResult, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {"_id": log_req.log_id, "user_id": log_req.user_id.clone()};
+    with_timeout(CONFIG.operation_timeout, my_coll.delete_one(filter)).await
+}
+
+// Removes every log of the user `CONFIG.delete_batch_size` at a time, so that no single
+// delete holds the collection for long.
+pub async fn log_delete_user(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<u64, mongodb::error::Error> {
+    delete_user_in_batches(mongodb_client, user_id, CONFIG.delete_batch_size).await
+}
+
+async fn delete_user_in_batches(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    batch_size: i64,
+) -> Result<u64, mongodb::error::Error> {
+    let my_coll: Collection<Document> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let options = FindOptions::builder()
+        .projection(doc! {"_id": 1})
+        .limit(batch_size)
+        .build();
+    let mut deleted = 0;
+    loop {
+        let batch: Vec<Document> = my_coll
+            .find(doc! {"user_id": user_id})
+            .with_options(options.clone())
+            .await?
+            .try_collect()
+            .await?;
+        let ids: Vec<ObjectId> = batch
+            .iter()
+            .filter_map(|log| log.get_object_id("_id").ok())
+            .collect();
+        if ids.is_empty() {
+            return Ok(deleted);
+        }
+        let res = my_coll.delete_many(doc! {"_id": {"$in": ids}}).await?;
+        deleted += res.deleted_count;
+    }
+}
+
+// A cutoff in the future would take the latest logs too, and all of them with a wrong
+// clock, so it is refused instead of being clamped
+pub async fn purge_older_than(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, NightlogError> {
+    if cutoff > Utc::now() {
+        let error = FieldError::new("cutoff", "must not be in the future");
+        return Err(NightlogError::Invalid(error));
+    }
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {"user_id": user_id, "date": {"$lt": date_bson(&cutoff)}};
+    let res = my_coll.delete_many(filter).await?;
+    Ok(res.deleted_count)
+}
+
+// The date becomes the one the planned observation was actually made on, the moon
+// illumination and the computed altitude derived from it go along. Returns the number
+// of logs modified.
+pub async fn log_mark_completed(
+    mongodb_client: &Client,
+    log_id: ObjectId,
+    user_id: &UserId,
+    date: DateTime<Utc>,
+) -> Result<u64, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {"_id": log_id, "user_id": user_id, "deleted_at": Bson::Null};
+    let Some(mut log) = my_coll.find_one(filter.clone()).await? else {
+        return Ok(0);
+    };
+    log.set_date(date);
+    let update = doc! {"$set": {
+        "observation.status": ObservationStatus::Completed.as_str(),
+        "observation.moon_illumination": log.observation.moon_illumination,
+        "observation.altitude_deg": log.observation.altitude_deg,
+        "observation.altitude_computed": log.observation.altitude_computed,
+        "date": date_bson(&date),
+        "updated_at": date_bson(&Utc::now()),
+    }};
+    let res = my_coll.update_one(filter, update).await?;
+    Ok(res.modified_count)
+}
+
+// The log stays in the collection, with `deleted_at` telling when it was deleted
+pub async fn log_soft_delete(
+    mongodb_client: &Client,
+    log_req: &DeleteLogRequest,
+) -> Result<UpdateResult, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {
+        "_id": log_req.log_id,
+        "user_id": log_req.user_id.clone(),
+        "deleted_at": Bson::Null,
+    };
+    let update = doc! {"$set": {"deleted_at": date_bson(&Utc::now())}};
+    my_coll.update_one(filter, update).await
+}
+
+// Brings a soft deleted log back, logs that aren't deleted are left untouched
+pub async fn log_undelete(
+    mongodb_client: &Client,
+    log_id: ObjectId,
+    user_id: &UserId,
+) -> Result<UpdateResult, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {
+        "_id": log_id,
+        "user_id": user_id,
+        "deleted_at": {"$ne": Bson::Null},
+    };
+    let update = doc! {
+        "$unset": {"deleted_at": ""},
+        "$set": {"updated_at": date_bson(&Utc::now())},
+    };
+    my_coll.update_one(filter, update).await
+}
+
+pub async fn log_count(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    include_deleted: bool,
+) -> Result<u64, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let mut filter = doc! {"user_id": user_id};
+    if !include_deleted {
+        filter.insert("deleted_at", Bson::Null);
+    }
+    my_coll.count_documents(filter).await
+}
+
+/// Whether the user has a log of the object, deleted logs aside. The count stops at
+/// the first match, so that it stays cheap for the objects seen many times.
+pub async fn has_observed(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    object_name: &str,
+) -> Result<bool, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {
+        "user_id": user_id,
+        "observation.object_name": object_name,
+        "deleted_at": Bson::Null,
+    };
+    let count = my_coll.count_documents(filter).limit(1).await?;
+    Ok(count > 0)
+}
+
+/// Soft deletes a log, returning it as it was before the delete.
+pub async fn log_soft_delete_returning(
+    mongodb_client: &Client,
+    log_req: &DeleteLogRequest,
+) -> Result<Option<Log>, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {
+        "_id": log_req.log_id,
+        "user_id": log_req.user_id.clone(),
+        "deleted_at": Bson::Null,
+    };
+    let update = doc! {"$set": {"deleted_at": date_bson(&Utc::now())}};
+    let update = my_coll.find_one_and_update(filter, update);
+    with_timeout(CONFIG.operation_timeout, update).await
+}
+
+pub async fn log_delete_returning(
+    mongodb_client: &Client,
+    log_req: &DeleteLogRequest,
+) -> Result<Option<Log>, NightlogError> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {"_id": log_req.log_id, "user_id": log_req.user_id.clone()};
+    with_timeout(
+        CONFIG.operation_timeout,
+        my_coll.find_one_and_delete(filter),
+    )
+    .await
+}
+
+/// Logs that can be plotted on a sky map.
+pub async fn log_with_coordinates(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<Log>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    // `$ne: null` also excludes documents missing the field
+    let filter = doc! {
+        "user_id": user_id,
+        "deleted_at": Bson::Null,
+        "observation.right_ascension": {"$ne": Bson::Null},
+        "observation.declination": {"$ne": Bson::Null},
+    };
+    my_coll.find(filter).await?.try_collect().await
+}
+
+// The night runs from noon to noon UTC, like `observing_night`
+pub async fn log_night(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    night: NaiveDate,
+) -> Result<Vec<Log>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let start = night.and_hms_opt(12, 0, 0).unwrap_or_default().and_utc();
+    let end = start + Duration::days(1);
+    let filter = doc! {
+        "user_id": user_id,
+        "deleted_at": Bson::Null,
+        "date": {"$gte": date_bson(&start), "$lt": date_bson(&end)},
+    };
+    let options = FindOptions::builder()
+        .sort(doc! {"date": 1, "_id": 1})
+        .build();
+    my_coll
+        .find(filter)
+        .with_options(options)
+        .await?
+        .try_collect()
+        .await
+}
+
+// Month and day are the UTC ones of `date`, the most recent year first
+pub async fn log_on_this_day(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    month: u32,
+    day: u32,
+) -> Result<Vec<Log>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {
+        "user_id": user_id,
+        "deleted_at": Bson::Null,
+        "$expr": {"$and": [
+            {"$eq": [{"$month": "$date"}, month as i32]},
+            {"$eq": [{"$dayOfMonth": "$date"}, day as i32]},
+        ]},
+    };
+    let options = FindOptions::builder()
+        .sort(doc! {"date": -1, "_id": -1})
+        .build();
+    my_coll
+        .find(filter)
+        .with_options(options)
+        .await?
+        .try_collect()
+        .await
+}
+
+// the logs left out by `log_with_coordinates`, missing either coordinate
+pub async fn log_missing_coordinates(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<Log>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    // matching null also matches documents missing the field
+    let filter = doc! {
+        "user_id": user_id,
+        "deleted_at": Bson::Null,
+        "$or": [
+            {"observation.right_ascension": Bson::Null},
+            {"observation.declination": Bson::Null},
+        ],
+    };
+    my_coll.find(filter).await?.try_collect().await
+}
+
+pub async fn find_future_dated(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<ObjectId>, mongodb::error::Error> {
+    let my_coll: Collection<Document> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {
+        "user_id": user_id,
+        "deleted_at": Bson::Null,
+        "date": {"$gt": date_bson(&Utc::now())},
+    };
+    let logs: Vec<Document> = my_coll
+        .find(filter)
+        .projection(doc! {"_id": 1})
+        .await?
+        .try_collect()
+        .await?;
+    Ok(logs
+        .iter()
+        .filter_map(|log| log.get_object_id("_id").ok())
+        .collect())
+}
+
+// How far from its catalog position a logged object can be: the catalog has no constellation
+// boundaries, so coordinates farther than this are taken to be in another constellation.
+const CATALOG_TOLERANCE_DEG: f64 = 5.0;
+
+// Logs of objects outside the catalog, or without coordinates, can't be checked and are
+// never reported.
+pub async fn detect_name_coordinate_conflicts(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<ObjectId>, mongodb::error::Error> {
+    let logs = log_with_coordinates(mongodb_client, user_id).await?;
+    Ok(logs
+        .iter()
+        .filter(|log| {
+            let observation = &log.observation;
+            let (Some(object), Some(ra), Some(dec)) = (
+                find_catalog_object(&observation.object_name),
+                observation.right_ascension,
+                observation.declination,
+            ) else {
+                return false;
+            };
+            angular_separation(ra, dec, object.right_ascension, object.declination)
+                > CATALOG_TOLERANCE_DEG
+        })
+        .filter_map(|log| log._id)
+        .collect())
+}
+
+// Every pair of logs is compared, which is fine for the logs of one user. Names are
+// compared through `resolve_alias`, so that "NGC 224" and "M31" don't conflict.
+pub async fn detect_coordinate_name_conflicts(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    tolerance_deg: f64,
+) -> Result<Vec<(ObjectId, ObjectId)>, mongodb::error::Error> {
+    let logs = log_with_coordinates(mongodb_client, user_id).await?;
+    let positioned: Vec<(ObjectId, String, f64, f64)> = logs
+        .iter()
+        .filter_map(|log| {
+            let observation = &log.observation;
+            Some((
+                log._id?,
+                resolve_alias(&observation.object_name),
+                observation.right_ascension?,
+                observation.declination?,
+            ))
+        })
+        .collect();
+    let mut conflicts = Vec::new();
+    for (i, (id, name, ra, dec)) in positioned.iter().enumerate() {
+        for (other_id, other_name, other_ra, other_dec) in &positioned[i + 1..] {
+            if !name.eq_ignore_ascii_case(other_name)
+                && angular_separation(*ra, *dec, *other_ra, *other_dec) <= tolerance_deg
+            {
+                conflicts.push((*id, *other_id));
+            }
+        }
+    }
+    Ok(conflicts)
+}
+
+// same ranges as `ObservationRequest::validate`
+fn invalid_right_ascension() -> Document {
+    doc! {"$or": [
+        {"observation.right_ascension": {"$lt": 0.0}},
+        {"observation.right_ascension": {"$gt": 360.0}},
+    ]}
+}
+
+fn invalid_declination() -> Document {
+    doc! {"$or": [
+        {"observation.declination": {"$lt": -90.0}},
+        {"observation.declination": {"$gt": 90.0}},
+    ]}
+}
+
+/// Ids of the user's logs with a right ascension or declination out of range.
+pub async fn validate_coordinates_report(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<ObjectId>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {
+        "user_id": user_id,
+        "$or": [invalid_right_ascension(), invalid_declination()],
+    };
+    let logs: Vec<Log> = my_coll.find(filter).await?.try_collect().await?;
+    Ok(logs.iter().filter_map(|log| log._id).collect())
+}
+
+/// Clears the out of range coordinates found by `validate_coordinates_report`, keeping
+/// the valid one when only one is wrong. Returns the ids of the repaired logs.
+pub async fn repair_invalid_coordinates(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<ObjectId>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let ids = validate_coordinates_report(mongodb_client, user_id).await?;
+    for (invalid, field) in [
+        (invalid_right_ascension(), "observation.right_ascension"),
+        (invalid_declination(), "observation.declination"),
+    ] {
+        let mut filter = invalid;
+        filter.insert("user_id", user_id);
+        my_coll
+            .update_many(filter, doc! {"$set": {field: Bson::Null}})
+            .await?;
+    }
+    Ok(ids)
+}
+
+// notes added to existing ones start a new paragraph
+const NOTES_SEPARATOR: &str = "\n\n";
+
+// The concatenation runs on the server, with an update pipeline, so that notes appended
+// at the same time can't overwrite each other.
+pub async fn log_append_note(
+    mongodb_client: &Client,
+    log_id: ObjectId,
+    user_id: &UserId,
+    text: &str,
+) -> Result<UpdateResult, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {"_id": log_id, "user_id": user_id, "deleted_at": Bson::Null};
+    let notes = doc! {"$ifNull": ["$observation.notes", ""]};
+    // a text starting with `$` would otherwise be taken for a field path
+    let text = doc! {"$literal": text};
+    let pipeline = vec![doc! {"$set": {
+        "observation.notes": {"$cond": {
+            "if": {"$eq": [notes.clone(), ""]},
+            "then": text.clone(),
+            "else": {"$concat": [notes, NOTES_SEPARATOR, text]},
+        }},
+        "updated_at": date_bson(&Utc::now()),
+    }}];
+    my_coll.update_one(filter, pipeline).await
+}
+
+/// Appends the notes of `merge_id` to the ones of `keep_id`, then deletes `merge_id`.
+/// Returns `None`, without touching anything, when either log isn't one of the user's
+/// or is soft deleted.
+pub async fn log_merge(
+    mongodb_client: &Client,
+    keep_id: ObjectId,
+    merge_id: ObjectId,
+    user_id: &UserId,
+) -> Result<Option<Log>, mongodb::error::Error> {
+    if keep_id == merge_id {
+        return Ok(None);
+    }
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let keep_filter = doc! {"_id": keep_id, "user_id": user_id, "deleted_at": Bson::Null};
+    let merge_filter = doc! {"_id": merge_id, "user_id": user_id, "deleted_at": Bson::Null};
+    let Some(kept) = my_coll.find_one(keep_filter.clone()).await? else {
+        return Ok(None);
+    };
+    let Some(merged) = my_coll.find_one(merge_filter.clone()).await? else {
+        return Ok(None);
+    };
+    let notes = match (
+        kept.observation.notes.is_empty(),
+        merged.observation.notes.is_empty(),
+    ) {
+        (_, true) => kept.observation.notes,
+        (true, false) => merged.observation.notes,
+        (false, false) => format!(
+            "{}{}{}",
+            kept.observation.notes, NOTES_SEPARATOR, merged.observation.notes
+        ),
+    };
+    let update = doc! {"$set": {
+        "observation.notes": notes,
+        "updated_at": date_bson(&Utc::now()),
+    }};
+    let updated = my_coll
+        .find_one_and_update(keep_filter, update)
+        .return_document(ReturnDocument::After)
+        .await?;
+    // the merged log only goes once its notes are saved in the kept one, a failure in
+    // between leaves the notes twice instead of losing them
+    if updated.is_some() {
+        my_coll
+            .delete_one(doc! {"_id": merged._id, "user_id": &merged.user_id})
+            .await?;
+    }
+    Ok(updated)
+}
+
+// AGGREGATIONS
+pub async fn log_total_observing_minutes(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<i64, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    // logs without a recorded duration count as zero minutes
+    let pipeline = vec![
+        doc! {"$match": {"user_id": user_id, "deleted_at": Bson::Null}},
+        doc! {"$group": {
+            "_id": Bson::Null,
+            "total": {"$sum": {"$ifNull": ["$observation.duration_minutes", 0]}},
+        }},
+    ];
+    let mut cursor = my_coll.aggregate(pipeline).await?;
+    let total = match cursor.try_next().await? {
+        Some(group) => bson_to_i64(group.get("total")),
+        None => 0,
+    };
+    Ok(total)
+}
+
+// first and last observation dates, none when the user has no logs
+pub async fn log_date_bounds(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Option<(DateTime<Utc>, DateTime<Utc>)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let pipeline = vec![
+        doc! {"$match": {"user_id": user_id, "deleted_at": Bson::Null}},
+        doc! {"$group": {
+            "_id": Bson::Null,
+            "first": {"$min": "$date"},
+            "last": {"$max": "$date"},
+        }},
+    ];
+    let mut cursor = my_coll.aggregate(pipeline).await?;
+    let Some(group) = cursor.try_next().await? else {
+        return Ok(None);
+    };
+    let bounds = match (group.get("first"), group.get("last")) {
+        (Some(first), Some(last)) => bson_to_datetime(first).zip(bson_to_datetime(last)),
+        _ => None,
+    };
+    Ok(bounds)
+}
+
+// One round-trip for the whole dashboard: every `$facet` branch works on the same matched logs
+pub async fn log_dashboard_stats(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<DashboardStats, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let pipeline = vec![
+        doc! {"$match": {"user_id": user_id, "deleted_at": Bson::Null}},
+        doc! {"$facet": {
+            "summary": [
+                {"$group": {
+                    "_id": Bson::Null,
+                    "total": {"$sum": 1},
+                    "first": {"$min": "$date"},
+                    "last": {"$max": "$date"},
+                }},
+            ],
+            "top_object": [
+                {"$group": {
+                    "_id": alias_expression("$observation.object_name"),
+                    "count": {"$sum": 1},
+                }},
+                {"$sort": {"count": -1, "_id": 1}},
+                {"$limit": 1},
+            ],
+            "monthly": [
+                {"$group": {
+                    "_id": {"$dateToString": {"format": "%Y-%m", "date": "$date"}},
+                    "count": {"$sum": 1},
+                }},
+                {"$sort": {"_id": 1}},
+            ],
+        }},
+    ];
+    let mut cursor = my_coll.aggregate(pipeline).await?;
+    let Some(facets) = cursor.try_next().await? else {
+        return Ok(DashboardStats::default());
+    };
+    let first_of = |facet: &str| -> Option<Document> {
+        facets
+            .get_array(facet)
+            .ok()?
+            .first()?
+            .as_document()
+            .cloned()
+    };
+    let mut stats = DashboardStats::default();
+    if let Some(summary) = first_of("summary") {
+        stats.total = bson_to_i64(summary.get("total")) as u64;
+        stats.first = summary.get("first").and_then(bson_to_datetime);
+        stats.last = summary.get("last").and_then(bson_to_datetime);
+    }
+    stats.top_object =
+        first_of("top_object").and_then(|top| top.get_str("_id").ok().map(str::to_owned));
+    stats.monthly_counts = facets
+        .get_array("monthly")
+        .map(|months| {
+            months
+                .iter()
+                .filter_map(|month| {
+                    let month = month.as_document()?;
+                    let name = month.get_str("_id").ok()?;
+                    Some((name.to_owned(), bson_to_i64(month.get("count")) as u64))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(stats)
+}
+
+// Nights are grouped on the start of the noon to noon day, like `observing_night` does
+pub async fn log_nightly_summary(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<NightSummary>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    const HALF_DAY_MS: i64 = 43_200_000;
+    let shifted = doc! {"$subtract": ["$date", HALF_DAY_MS]};
+    let pipeline = vec![
+        doc! {"$match": {"user_id": user_id, "deleted_at": Bson::Null}},
+        doc! {"$sort": {"date": 1}},
+        doc! {"$group": {
+            "_id": {"$dateToString": {"format": "%Y-%m-%d", "date": shifted}},
+            "objects": {"$push": "$observation.object_name"},
+        }},
+        doc! {"$sort": {"_id": 1}},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|group| {
+            let date = NaiveDate::parse_from_str(group.get_str("_id").ok()?, "%Y-%m-%d").ok()?;
+            let objects = group
+                .get_array("objects")
+                .ok()?
+                .iter()
+                .filter_map(|name| name.as_str().map(str::to_owned))
+                .collect();
+            Some(NightSummary { date, objects })
+        })
+        .collect())
+}
+
+// `%G` and `%V` are the ISO week-numbering year and week, so the last days of December
+// can fall in week 1 of the following year
+pub async fn log_weekly_counts(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<(String, u64)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let pipeline = vec![
+        doc! {"$match": {"user_id": user_id, "deleted_at": Bson::Null}},
+        doc! {"$group": {
+            "_id": {"$dateToString": {"format": "%G-W%V", "date": "$date"}},
+            "count": {"$sum": 1},
+        }},
+        doc! {"$sort": {"_id": 1}},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|group| {
+            let week = group.get_str("_id").ok()?;
+            Some((week.to_owned(), bson_to_i64(group.get("count")) as u64))
+        })
+        .collect())
+}
+
+// Windows are counted from the unix epoch, so that a bucket keeps its boundaries as logs
+// are added. Each one is named after its first day, and only windows with logs are reported.
+pub async fn log_frequency_trend(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    window_days: i64,
+) -> Result<Vec<(String, u64)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let window_ms = window_days.max(1) * 86_400_000;
+    let window_start = doc! {"$toDate": {"$multiply": [
+        {"$floor": {"$divide": [{"$toLong": "$date"}, window_ms]}},
+        window_ms,
+    ]}};
+    let pipeline = vec![
+        doc! {"$match": {"user_id": user_id, "deleted_at": Bson::Null}},
+        doc! {"$group": {
+            "_id": {"$dateToString": {"format": "%Y-%m-%d", "date": window_start}},
+            "count": {"$sum": 1},
+        }},
+        doc! {"$sort": {"_id": 1}},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|group| {
+            let window = group.get_str("_id").ok()?;
+            Some((window.to_owned(), bson_to_i64(group.get("count")) as u64))
+        })
+        .collect())
+}
+
+// Every bucket is reported, in phase order, logs without an illumination are left out
+pub async fn log_moonphase_distribution(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<(String, u64)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let branches: Vec<Document> = MOON_PHASE_BUCKETS
+        .iter()
+        .filter(|(_, bound)| bound.is_finite())
+        .map(|(name, bound)| {
+            doc! {"case": {"$lt": ["$observation.moon_illumination", bound]}, "then": name}
+        })
+        .collect();
+    let pipeline = vec![
+        doc! {"$match": {
+            "user_id": user_id,
+            "deleted_at": Bson::Null,
+            "observation.moon_illumination": {"$ne": Bson::Null},
+        }},
+        doc! {"$group": {
+            "_id": {"$switch": {
+                "branches": branches,
+                "default": MOON_PHASE_BUCKETS[MOON_PHASE_BUCKETS.len() - 1].0,
+            }},
+            "count": {"$sum": 1},
+        }},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(MOON_PHASE_BUCKETS
+        .iter()
+        .map(|(name, _)| {
+            let count = groups
+                .iter()
+                .find(|group| group.get_str("_id") == Ok(name))
+                .map_or(0, |group| bson_to_i64(group.get("count")) as u64);
+            (name.to_string(), count)
+        })
+        .collect())
+}
+
+pub async fn log_current_streak(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<u32, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let dates = my_coll
+        .distinct("date", doc! {"user_id": user_id, "deleted_at": Bson::Null})
+        .await?;
+    let nights: Vec<NaiveDate> = dates
+        .iter()
+        .filter_map(bson_to_datetime)
+        .map(|date| observing_night(&date))
+        .collect();
+    Ok(current_streak(&nights))
+}
+
+// Only the dates are fetched, the gaps are found on them like `current_streak` does
+pub async fn find_date_gaps(
+    mongodb_client: &Client,
+    user_id: &UserId,
+    max_gap_days: i64,
+) -> Result<Vec<(DateTime<Utc>, DateTime<Utc>)>, mongodb::error::Error> {
+    let my_coll: Collection<Document> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let options = FindOptions::builder().projection(doc! {"date": 1}).build();
+    let logs: Vec<Document> = my_coll
+        .find(doc! {"user_id": user_id, "deleted_at": Bson::Null})
+        .with_options(options)
+        .await?
+        .try_collect()
+        .await?;
+    let dates: Vec<DateTime<Utc>> = logs
+        .iter()
+        .filter_map(|log| bson_to_datetime(log.get("date")?))
+        .collect();
+    Ok(date_gaps(&dates, Duration::days(max_gap_days)))
+}
+
+/// Ranks users by number of logs, across all users: not meant to be exposed to them.
+/// Each user comes with the experience level of their preferences, to group the results.
+pub async fn observation_leaderboard(
+    mongodb_client: &Client,
+    limit: i64,
+) -> Result<Vec<LeaderboardEntry>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    // preferences are looked up after the limit, only for the users that made it
+    let pipeline = vec![
+        doc! {"$match": {"deleted_at": Bson::Null}},
+        doc! {"$group": {"_id": "$user_id", "count": {"$sum": 1}}},
+        doc! {"$sort": {"count": -1, "_id": 1}},
+        doc! {"$limit": limit},
+        doc! {"$lookup": {
+            "from": &CONFIG.prefs_collection,
+            "localField": "_id",
+            "foreignField": "user_id",
+            "as": "prefs",
+        }},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|group| {
+            let user_id = group.get_str("_id").ok()?;
+            let experience_level = group
+                .get_array("prefs")
+                .ok()
+                .and_then(|prefs| prefs.first()?.as_document()?.get("experience_level"))
+                .and_then(|level| mongodb::bson::from_bson(level.clone()).ok());
+            Some(LeaderboardEntry {
+                user_id: user_id.into(),
+                count: bson_to_i64(group.get("count")) as u64,
+                experience_level,
+            })
+        })
+        .collect())
+}
+
+pub async fn log_equipment_stats(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<(String, u64)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let pipeline = vec![
+        doc! {"$match": {"user_id": user_id, "deleted_at": Bson::Null}},
+        doc! {"$group": {"_id": "$observation.equipment", "count": {"$sum": 1}}},
+        doc! {"$sort": {"count": -1, "_id": 1}},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|group| {
+            let equipment = group.get_str("_id").ok()?;
+            Some((equipment.to_owned(), bson_to_i64(group.get("count")) as u64))
+        })
+        .collect())
+}
+
+// most used first, ties in tag order
+pub async fn log_tag_counts(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<(String, u64)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let pipeline = vec![
+        doc! {"$match": {"user_id": user_id, "deleted_at": Bson::Null}},
+        doc! {"$unwind": "$tags"},
+        doc! {"$group": {"_id": "$tags", "count": {"$sum": 1}}},
+        doc! {"$sort": {"count": -1, "_id": 1}},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|group| {
+            let tag = group.get_str("_id").ok()?;
+            Some((tag.to_owned(), bson_to_i64(group.get("count")) as u64))
+        })
+        .collect())
+}
+
+// The darkest sky (highest limiting magnitude) comes first, then the brightest. Logs
+// without a limiting magnitude are left out, so there is either none or both.
+pub async fn log_sky_quality_extremes(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Option<(Log, Log)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let filter = doc! {
+        "user_id": user_id,
+        "deleted_at": Bson::Null,
+        "observation.limiting_magnitude": {"$ne": Bson::Null},
+    };
+    let darkest = my_coll
+        .find_one(filter.clone())
+        .sort(doc! {"observation.limiting_magnitude": -1, "date": -1})
+        .await?;
+    let brightest = my_coll
+        .find_one(filter)
+        .sort(doc! {"observation.limiting_magnitude": 1, "date": -1})
+        .await?;
+    Ok(darkest.zip(brightest))
+}
+
+// most used setup first, like `log_equipment_stats`
+pub async fn log_setup_stats(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<((String, String), u64)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let pipeline = vec![
+        doc! {"$match": {"user_id": user_id, "deleted_at": Bson::Null}},
+        doc! {"$group": {
+            "_id": {"equipment": "$observation.equipment", "eyepiece": "$observation.eyepiece"},
+            "count": {"$sum": 1},
+        }},
+        doc! {"$sort": {"count": -1, "_id.equipment": 1, "_id.eyepiece": 1}},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|group| {
+            let setup = group.get_document("_id").ok()?;
+            let equipment = setup.get_str("equipment").ok()?.to_owned();
+            let eyepiece = setup.get_str("eyepiece").ok()?.to_owned();
+            Some((
+                (equipment, eyepiece),
+                bson_to_i64(group.get("count")) as u64,
+            ))
+        })
+        .collect())
+}
+
+// unrated logs are left out rather than counted as zero
+pub async fn log_avg_rating_by_object(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<(String, f64)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let pipeline = vec![
+        doc! {"$match": {
+            "user_id": user_id,
+            "deleted_at": Bson::Null,
+            "observation.rating": {"$ne": Bson::Null},
+        }},
+        doc! {"$group": {
+            "_id": alias_expression("$observation.object_name"),
+            "rating": {"$avg": "$observation.rating"},
+        }},
+        doc! {"$sort": {"_id": 1}},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|group| {
+            let object_name = group.get_str("_id").ok()?;
+            Some((object_name.to_owned(), group.get_f64("rating").ok()?))
+        })
+        .collect())
+}
+
+pub async fn log_distinct_sites(
+    mongodb_client: &Client,
+    user_id: &UserId,
+) -> Result<Vec<(f64, f64)>, mongodb::error::Error> {
+    let my_coll: Collection<Log> = mongodb_client
+        .database(&CONFIG.database_name)
+        .collection(&CONFIG.database_collection);
+    let pipeline = vec![
+        doc! {"$match": {
+            "user_id": user_id,
+            "deleted_at": Bson::Null,
+            "observation.site_latitude": {"$ne": Bson::Null},
+            "observation.site_longitude": {"$ne": Bson::Null},
+        }},
+        doc! {"$group": {"_id": {
+            "latitude": "$observation.site_latitude",
+            "longitude": "$observation.site_longitude",
+        }}},
+        doc! {"$sort": {"_id.latitude": 1, "_id.longitude": 1}},
+    ];
+    let groups = my_coll
+        .aggregate(pipeline)
+        .await?
+        .try_collect::<Vec<Document>>()
+        .await?;
+    Ok(groups
+        .iter()
+        .filter_map(|group| {
+            let site = group.get_document("_id").ok()?;
+            Some((
+                site.get_f64("latitude").ok()?,
+                site.get_f64("longitude").ok()?,
+            ))
+        })
+        .collect())
+}
+
+// stored dates are bson dates, with millisecond precision
+fn date_bson(date: &DateTime<Utc>) -> Bson {
+    Bson::DateTime(mongodb::bson::DateTime::from_millis(
+        date.timestamp_millis(),
+    ))
+}
+
+// unix timestamps are still read, for the logs stored before `migrate_integer_dates`
+fn bson_to_datetime(value: &Bson) -> Option<DateTime<Utc>> {
+    match value {
+        Bson::DateTime(date) => DateTime::from_timestamp_millis(date.timestamp_millis()),
+        Bson::Int32(secs) => DateTime::from_timestamp(*secs as i64, 0),
+        Bson::Int64(secs) => DateTime::from_timestamp(*secs, 0),
+        _ => None,
+    }
+}
+
+// numeric aggregation results can come back as any of the bson number types
+fn bson_to_i64(value: Option<&Bson>) -> i64 {
+    match value {
+        Some(Bson::Int32(n)) => *n as i64,
+        Some(Bson::Int64(n)) => *n,
+        Some(Bson::Double(n)) => *n as i64,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+#[allow(
+    clippy::needless_late_init,
+    clippy::clone_on_copy,
+    clippy::single_match
+)]
+mod tests {
+    use crate::{
+        angular_separation, archive_old_logs, backfill_moon_phase, backup_user_logs,
+        build_list_filter, build_search_filter, canonical_constellation, compute_altitude,
+        configure_client_options, current_streak, date_bson, date_gaps, delete_user_in_batches,
+        detect_coordinate_name_conflicts, detect_name_coordinate_conflicts, diff_observations,
+        ensure_indexes, estimate_limiting_magnitude, find_catalog_object, find_date_gaps,
+        find_future_dated, greenwich_sidereal_time, has_observed, is_above_horizon, julian_date,
+        list_limit, local_sidereal_time, log_add_tag_bulk, log_append_note,
+        log_avg_rating_by_object, log_count, log_create_share, log_current_streak,
+        log_dashboard_stats, log_date_bounds, log_delete_returning, log_delete_user, log_deletion,
+        log_diff, log_distinct_sites, log_equipment_stats, log_export_csv, log_frequency_trend,
+        log_get_by_share, log_history, log_import, log_insertion, log_list_page, log_listing,
+        log_listing_capped, log_listing_ids, log_mark_completed, log_merge,
+        log_missing_coordinates, log_moonphase_distribution, log_night, log_nightly_summary,
+        log_on_this_day, log_replacement, log_retrieval, log_retrieval_many, log_search,
+        log_separation, log_setup_stats, log_share_card, log_sky_quality_extremes, log_soft_delete,
+        log_soft_delete_returning, log_tag_counts, log_to_nexstar, log_total_observing_minutes,
+        log_undelete, log_weekly_counts, log_with_coordinates, log_with_neighbors, logs_to_csv,
+        logs_to_ical, magnification, migrate_integer_dates, mongodb_connection, moon_illumination,
+        observation_leaderboard, observation_schema, purge_older_than, recompute_derived,
+        rename_equipment, rename_user, render_notes_html, repair_invalid_coordinates, replace_log,
+        resolve_alias, resolve_app_name, resolve_database_name, scan_invalid_documents,
+        session_report, to_api_json, validate_coordinates_report, verify_indexes, with_retry,
+        with_timeout, ApiResponse, AuditEntry, Config, DashboardStats, DeleteLogRequest,
+        ExperienceLevel, ExportRequest, Features, FieldDiff, FieldError, GetListRequest,
+        GetLogRequest, ImportOutcome, ImportRequest, JsonStyle, Log, LogFormat, LogPairRequest,
+        Neighbor, NightSummary, NightlogError, Observation, ObservationRequest, ObservationStatus,
+        RenameReport, ReplaceLogRequest, SearchLogRequest, ShareCardRequest, SortOrder, TagFilter,
+        UserId, UserPrefs, CATALOG, CLIENT_OPTIONS_PARSES, CONFIG, DEFAULT_SOURCE,
+        MAX_LIST_RESULTS, OBSERVATION_REQUEST_FIELDS, SHARE_PREVIEW_LENGTH,
+    };
+    use chrono::{DateTime, Duration, NaiveDate, SecondsFormat, TimeZone, Utc};
+    use futures::TryStreamExt;
+    use mongodb::{
+        bson::{doc, oid::ObjectId, Bson, Document},
+        options::ClientOptions,
+        Client, Collection,
+    };
+    use std::sync::atomic::Ordering;
+
+    const USER_ID: &str = "fake_id";
+
+    #[tokio::test]
+    async fn db_connection_test() {
+        let client = mongodb_connection().await;
+        assert!(client.is_ok());
+    }
+
+    #[test]
+    fn log_creation_test() {
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            object_location: "Andromeda".to_string(),
+            equipment: "Dobson 254/1250".to_string(),
+            eyepiece: "25mm".to_string(),
+            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+
+        assert_eq!(req.user_id, log.user_id);
+        assert_eq!(req.object_name, log.observation.object_name);
+        assert_eq!(req.object_location, log.observation.object_location);
+        assert_eq!(req.equipment, log.observation.equipment);
+        assert_eq!(req.eyepiece, log.observation.eyepiece);
+        assert_eq!(req.notes, log.observation.notes);
+    }
+
+    #[tokio::test]
+    async fn log_insertion_test() {
+        let client = mongodb_connection().await.unwrap();
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            object_location: "Andromeda".to_string(),
+            equipment: "Dobson 254/1250".to_string(),
+            eyepiece: "25mm".to_string(),
+            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let res;
+        match log_insertion(&log, &client).await {
+            Ok(option_id) => res = option_id,
+            Err(e) => {
+                println!("res is error: {:?}", e);
+                panic!();
+            }
+        };
+        let res = res.unwrap();
+        let get_req = GetLogRequest {
+            user_id: USER_ID.into(),
+            log_id: res.clone(),
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        let saved = log_retrieval(&client, &get_req).await.unwrap();
+        assert!(saved.is_some());
+        let delete_req = DeleteLogRequest {
+            user_id: USER_ID.into(),
+            log_id: res,
+        };
+        let deleted = log_deletion(&client, &delete_req).await.unwrap();
+        assert_eq!(deleted.deleted_count, 1);
+    }
+
+    #[tokio::test]
+    async fn log_deletion_test() {
+        let client = mongodb_connection().await.unwrap();
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            object_location: "Andromeda".to_string(),
+            equipment: "Dobson 254/1250".to_string(),
+            eyepiece: "25mm".to_string(),
+            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let res;
+        match log_insertion(&log, &client).await {
+            Ok(option_id) => res = option_id,
+            Err(e) => {
+                println!("res is error: {:?}", e);
+                panic!();
+            }
+        };
+        let res = res.unwrap();
+        let get_req = GetLogRequest {
+            user_id: USER_ID.into(),
+            log_id: res.clone(),
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        let saved = log_retrieval(&client, &get_req).await.unwrap();
+        assert!(saved.is_some());
+        let delete_req = DeleteLogRequest {
+            user_id: USER_ID.into(),
+            log_id: res,
+        };
+        let deleted = log_deletion(&client, &delete_req).await.unwrap();
+        assert_eq!(deleted.deleted_count, 1);
+
+        let saved = log_retrieval(&client, &get_req).await.unwrap();
+        assert!(saved.is_none());
+    }
+
+    #[tokio::test]
+    async fn log_retrieving_test() {
+        let client = mongodb_connection().await.unwrap();
+        let object_name = "M31".to_string();
+        let object_location = "Andromeda".to_string();
+        let equipment = "Dobson 254/1250".to_string();
+        let eyepiece = "25mm".to_string();
+        let notes = "beautiful, even with a bad seeing".to_string();
+
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: object_name.clone(),
+            object_location: object_location.clone(),
+            equipment: equipment.clone(),
+            eyepiece: eyepiece.clone(),
+            notes: notes.clone(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let res;
+        match log_insertion(&log, &client).await {
+            Ok(option_id) => res = option_id,
+            Err(e) => {
+                println!("res is error: {:?}", e);
+                panic!();
+            }
+        };
+        let res = res.unwrap();
+        let get_req = GetLogRequest {
+            user_id: USER_ID.into(),
+            log_id: res.clone(),
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        let saved = log_retrieval(&client, &get_req).await.unwrap().unwrap();
+
+        assert_eq!(saved.user_id, UserId::from(USER_ID));
+        assert_eq!(saved.observation.object_name, object_name);
+        assert_eq!(saved.observation.object_location, object_location);
+        assert_eq!(saved.observation.equipment, equipment);
+        assert_eq!(saved.observation.eyepiece, eyepiece);
+        assert_eq!(saved.observation.notes, notes);
+
+        let delete_req = DeleteLogRequest {
+            user_id: USER_ID.into(),
+            log_id: res,
+        };
+        let deleted = log_deletion(&client, &delete_req).await.unwrap();
+        assert_eq!(deleted.deleted_count, 1);
+    }
+
+    #[tokio::test]
+    async fn log_replacing_test() {
+        let client = mongodb_connection().await.unwrap();
+        let req_1 = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            object_location: "Andromeda".to_string(),
+            equipment: "Dobson 254/1250".to_string(),
+            eyepiece: "25mm".to_string(),
+            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req_1);
+        let res;
+        match log_insertion(&log, &client).await {
+            Ok(option_id) => res = option_id,
+            Err(e) => {
+                println!("res is error: {:?}", e);
+                panic!();
+            }
+        };
+        let res = res.unwrap();
+        let get_req = GetLogRequest {
+            user_id: USER_ID.into(),
+            log_id: res.clone(),
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        let saved = log_retrieval(&client, &get_req).await.unwrap();
+        assert!(saved.is_some());
+
+        //replace
+        let req_2 = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M1".to_string(),
+            object_location: "Taurus".to_string(),
+            equipment: "Dobson 254/1200".to_string(),
+            eyepiece: "10mm".to_string(),
+            notes: "crab nebula".to_string(),
+            ..Default::default()
+        };
+        let mut log_2 = Log::from_observation_request(&req_2);
+        log_2._id = Some(res.clone());
+        let rep = log_replacement(&log_2, &client).await.unwrap();
+        assert_eq!(rep.modified_count, 1);
+
+        let replaced = log_retrieval(&client, &get_req).await.unwrap().unwrap();
+        assert_eq!(replaced.user_id, log_2.user_id);
+        assert_eq!(
+            replaced.observation.object_name,
+            log_2.observation.object_name
+        );
+        assert_eq!(
+            replaced.observation.object_location,
+            log_2.observation.object_location
+        );
+        assert_eq!(replaced.observation.equipment, log_2.observation.equipment);
+        assert_eq!(replaced.observation.eyepiece, log_2.observation.eyepiece);
+        assert_eq!(replaced.observation.notes, log_2.observation.notes);
+        //delete
+        let delete_req = DeleteLogRequest {
+            user_id: USER_ID.into(),
+            log_id: res,
+        };
+        let deleted = log_deletion(&client, &delete_req).await.unwrap();
+        assert_eq!(deleted.deleted_count, 1);
+    }
+
+    #[tokio::test]
+    async fn log_listing_test() {
+        let client = mongodb_connection().await.unwrap();
+        let req_1 = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            object_location: "Andromeda".to_string(),
+            equipment: "Dobson 254/1250".to_string(),
+            eyepiece: "25mm".to_string(),
+            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req_1);
+        match log_insertion(&log, &client).await {
+            Err(e) => {
+                println!("res is error: {:?}", e);
+                panic!();
+            }
+            _ => {}
+        };
+
+        let req_2 = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M1".to_string(),
+            object_location: "Taurus".to_string(),
+            equipment: "Dobson 254/1200".to_string(),
+            eyepiece: "10mm".to_string(),
+            notes: "crab nebula".to_string(),
+            ..Default::default()
+        };
+        let log_2 = Log::from_observation_request(&req_2);
+        match log_insertion(&log_2, &client).await {
+            Err(e) => {
+                println!("res is error: {:?}", e);
+                panic!();
+            }
+            _ => {}
+        };
+
+        //test
+        let list_req = GetListRequest {
+            user_id: "fake_id".into(),
+            ..Default::default()
+        };
+        let cursor = log_listing(&client, &list_req).await.unwrap();
+        let list = match cursor.try_collect::<Vec<Log>>().await {
+            Ok(vector) => vector,
+            Err(e) => {
+                panic!(
+                    "an error occurred in collecting user's logs in a vector: {}",
+                    e
+                );
+            }
+        };
+        assert_eq!(list.len(), 2);
+
+        //delete
+        for log in list {
+            let delete_req = DeleteLogRequest {
+                user_id: USER_ID.into(),
+                log_id: log._id.unwrap(),
+            };
+            let deleted = log_deletion(&client, &delete_req).await.unwrap();
+            assert_eq!(deleted.deleted_count, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn log_total_observing_minutes_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_duration";
+        let mut ids = Vec::new();
+        for (object_name, duration_minutes) in [("M31", Some(90)), ("M1", Some(45)), ("M42", None)]
+        {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                duration_minutes,
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let total = log_total_observing_minutes(&client, &user_id.into())
+            .await
+            .unwrap();
+        assert_eq!(total, 135);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            let deleted = log_deletion(&client, &delete_req).await.unwrap();
+            assert_eq!(deleted.deleted_count, 1);
+        }
+    }
+
+    #[test]
+    fn log_modified_since_test() {
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        let updated_at = log.updated_at.unwrap();
+
+        assert!(log.modified_since(&(updated_at - Duration::minutes(1))));
+        assert!(!log.modified_since(&updated_at));
+        assert!(!log.modified_since(&(updated_at + Duration::minutes(1))));
+
+        log.updated_at = None;
+        assert!(log.modified_since(&updated_at));
+    }
+
+    #[test]
+    fn canonical_constellation_test() {
+        assert_eq!(canonical_constellation("orion"), Some("Orion"));
+        assert_eq!(canonical_constellation(" URSA major "), Some("Ursa Major"));
+        assert_eq!(canonical_constellation("Orionis"), None);
+    }
+
+    #[tokio::test]
+    async fn log_listing_by_constellation_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_constellation";
+        for (object_name, constellation) in [("M42", "orion"), ("M31", "Andromeda")] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                constellation: Some(constellation.to_string()),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            log_insertion(&log, &client).await.unwrap();
+        }
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            constellation: Some("ORION".to_string()),
+            ..Default::default()
+        };
+        let cursor = log_listing(&client, &list_req).await.unwrap();
+        let list = cursor.try_collect::<Vec<Log>>().await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].observation.object_name, "M42");
+        assert_eq!(list[0].observation.constellation.as_deref(), Some("Orion"));
+
+        //delete
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            ..Default::default()
+        };
+        let cursor = log_listing(&client, &list_req).await.unwrap();
+        for log in cursor.try_collect::<Vec<Log>>().await.unwrap() {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id: log._id.unwrap(),
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn flexible_date_test() {
+        let log_json = |date: &str| {
+            format!(
+                r#"{{"_id": null, "user_id": "{}", "date": {}, "observation": {{"object_name": "M31",
+                "object_location": "Andromeda", "equipment": "", "eyepiece": "", "notes": ""}}}}"#,
+                USER_ID, date
+            )
+        };
+        let from_timestamp: Log = serde_json::from_str(&log_json("1700000000")).unwrap();
+        let from_rfc3339: Log =
+            serde_json::from_str(&log_json(r#""2023-11-14T22:13:20Z""#)).unwrap();
+        let from_offset: Log =
+            serde_json::from_str(&log_json(r#""2023-11-15T00:13:20+02:00""#)).unwrap();
+        assert_eq!(from_timestamp.date, from_rfc3339.date);
+        assert_eq!(from_timestamp.date, from_offset.date);
+        assert_eq!(from_timestamp.date.timestamp(), 1700000000);
+
+        let mut public = from_rfc3339.to_public();
+        public.updated_at = Some(from_rfc3339.date);
+        let serialized = serde_json::to_value(public).unwrap();
+        assert_eq!(serialized["date"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(serialized["updatedAt"], "2023-11-14T22:13:20.000Z");
+
+        // requests take dates both ways too
+        let get_req = |since: &str| {
+            let json = format!(
+                r#"{{"logId": {{"$oid": "65f0c0ffee0000000000beef"}}, "userId": "{}", "ifModifiedSince": {}}}"#,
+                USER_ID, since
+            );
+            serde_json::from_str::<GetLogRequest>(&json).unwrap()
+        };
+        assert_eq!(
+            get_req("1700000000").if_modified_since,
+            Some(from_timestamp.date)
+        );
+        assert_eq!(
+            get_req(r#""2023-11-14T22:13:20Z""#).if_modified_since,
+            Some(from_timestamp.date)
+        );
+        assert_eq!(get_req("null").if_modified_since, None);
+
+        assert!(serde_json::from_str::<Log>(&log_json(r#""yesterday""#)).is_err());
+    }
+
+    #[test]
+    fn current_streak_test() {
+        let night = |day| NaiveDate::from_ymd_opt(2024, 3, day).unwrap();
+        assert_eq!(current_streak(&[]), 0);
+        assert_eq!(current_streak(&[night(1)]), 1);
+        assert_eq!(
+            current_streak(&[night(5), night(1), night(4), night(3), night(5)]),
+            3
+        );
+        assert_eq!(current_streak(&[night(1), night(2), night(4)]), 1);
+    }
+
+    #[tokio::test]
+    async fn log_current_streak_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_streak";
+        // the gap is on the 2nd, the last night has two logs, one after midnight
+        let dates = [
+            Utc.with_ymd_and_hms(2024, 3, 1, 22, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 3, 3, 22, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 3, 4, 23, 30, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 3, 5, 21, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 3, 6, 1, 30, 0).unwrap(),
+        ];
+        let mut ids = Vec::new();
+        for date in dates {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: "M31".to_string(),
+                ..Default::default()
+            };
+            let mut log = Log::from_observation_request(&req);
+            log.date = date;
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let streak = log_current_streak(&client, &user_id.into()).await.unwrap();
+        assert_eq!(streak, 3);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn api_response_headers_test() {
+        let resp = ApiResponse::new(200, "[]".to_string());
+        let serialized = serde_json::to_value(&resp).unwrap();
+        assert_eq!(
+            serialized,
+            serde_json::json!({"statusCode": 200, "body": "[]"})
+        );
+
+        let resp = resp.with_header("Access-Control-Allow-Origin", "*");
+        let serialized = serde_json::to_value(&resp).unwrap();
+        assert_eq!(
+            serialized,
+            serde_json::json!({
+                "statusCode": 200,
+                "headers": {"Access-Control-Allow-Origin": "*"},
+                "body": "[]",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn log_listing_by_eyepiece_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_eyepiece";
+        let mut ids = Vec::new();
+        for (object_name, eyepiece) in [("M31", "25mm"), ("M1", "10mm"), ("M42", "25mm")] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                eyepiece: eyepiece.to_string(),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            eyepiece: Some("10mm".to_string()),
+            ..Default::default()
+        };
+        let cursor = log_listing(&client, &list_req).await.unwrap();
+        let list = cursor.try_collect::<Vec<Log>>().await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].observation.object_name, "M1");
+        assert_eq!(list[0].observation.eyepiece, "10mm");
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_indexes_test() {
+        let client = mongodb_connection().await.unwrap();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        // the collection must exist to be able to drop its indexes
+        ensure_indexes(&client).await.unwrap();
+        my_coll.drop_indexes().await.unwrap();
+        assert!(!verify_indexes(&client).await.unwrap());
+
+        ensure_indexes(&client).await.unwrap();
+        assert!(verify_indexes(&client).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn log_list_page_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_page";
+        let mut ids = Vec::new();
+        for object_name in ["M1", "M13", "M31", "M42", "M57"] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let mut seen = Vec::new();
+        for (skip, expected_len, expected_more) in [(0, 2, true), (2, 2, true), (4, 1, false)] {
+            let list_req = GetListRequest {
+                user_id: user_id.into(),
+                skip: Some(skip),
+                limit: Some(2),
+                ..Default::default()
+            };
+            let page = log_list_page(&client, &list_req).await.unwrap();
+            assert_eq!(page.total, 5);
+            assert_eq!(page.items.len(), expected_len);
+            assert_eq!(page.has_more, expected_more);
+            seen.extend(page.items.into_iter().map(|log| log._id.unwrap()));
+        }
+        seen.sort();
+        ids.sort();
+        assert_eq!(seen, ids);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn is_above_horizon_test() {
+        // Meeus, example 12.a: 1987 April 10 at 0h UT is 13h10m46.3668s
+        let meeus = Utc.with_ymd_and_hms(1987, 4, 10, 0, 0, 0).unwrap();
+        assert!((greenwich_sidereal_time(&meeus) - 197.693_195).abs() < 1e-4);
+
+        let (site_lat, site_lon) = (45.0, 9.19);
+        let start = Utc.with_ymd_and_hms(2024, 1, 15, 0, 0, 0).unwrap();
+        for hour in 0..24 {
+            let at = start + Duration::hours(hour);
+            // Polaris is circumpolar from mid-northern latitudes
+            assert!(is_above_horizon(37.95, 89.26, site_lat, site_lon, at));
+            // sigma Octantis never rises there
+            assert!(!is_above_horizon(317.2, -88.96, site_lat, site_lon, at));
+        }
+        // from the southern hemisphere it's the other way around
+        assert!(!is_above_horizon(37.95, 89.26, -33.9, 18.4, start));
+        assert!(is_above_horizon(317.2, -88.96, -33.9, 18.4, start));
+    }
+
+    #[tokio::test]
+    async fn log_merge_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_merge";
+        let mut ids = Vec::new();
+        for notes in [
+            "beautiful, even with a bad seeing",
+            "dust lanes visible",
+            "deleted",
+        ] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: "M31".to_string(),
+                notes: notes.to_string(),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        // another user can't merge them
+        let merged = log_merge(&client, ids[0], ids[1], &USER_ID.into())
+            .await
+            .unwrap();
+        assert!(merged.is_none());
+
+        // nor can a deleted log be merged
+        let deleted_req = DeleteLogRequest {
+            user_id: user_id.into(),
+            log_id: ids[2],
+        };
+        log_soft_delete(&client, &deleted_req).await.unwrap();
+        let merged = log_merge(&client, ids[0], ids[2], &user_id.into())
+            .await
+            .unwrap();
+        assert!(merged.is_none());
+        let merged = log_merge(&client, ids[2], ids[0], &user_id.into())
+            .await
+            .unwrap();
+        assert!(merged.is_none());
+
+        let merged = log_merge(&client, ids[0], ids[1], &user_id.into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(merged._id, Some(ids[0]));
+        assert!(merged
+            .observation
+            .notes
+            .contains("beautiful, even with a bad seeing"));
+        assert!(merged.observation.notes.contains("dust lanes visible"));
+
+        let get_req = GetLogRequest {
+            user_id: user_id.into(),
+            log_id: ids[1],
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        assert!(log_retrieval(&client, &get_req).await.unwrap().is_none());
+
+        //delete
+        let delete_req = DeleteLogRequest {
+            user_id: user_id.into(),
+            log_id: ids[0],
+        };
+        let deleted = log_deletion(&client, &delete_req).await.unwrap();
+        assert_eq!(deleted.deleted_count, 1);
+        let deleted = log_deletion(&client, &deleted_req).await.unwrap();
+        assert_eq!(deleted.deleted_count, 1);
+    }
+
+    #[test]
+    fn resolve_database_name_test() {
+        let resolve =
+            |database_name: Option<&str>, base_name: Option<&str>, deploy_env: Option<&str>| {
+                resolve_database_name(
+                    database_name.map(str::to_string),
+                    base_name.map(str::to_string),
+                    deploy_env.map(str::to_string),
+                )
+            };
+        assert_eq!(
+            resolve(None, None, Some("staging")).as_deref(),
+            Some("nightlog_staging")
+        );
+        assert_eq!(
+            resolve(None, None, Some("prod")).as_deref(),
+            Some("nightlog_prod")
+        );
+        assert_eq!(
+            resolve(None, Some("observations"), Some("PROD")).as_deref(),
+            Some("observations_prod")
+        );
+        assert_eq!(
+            resolve(Some("custom"), None, Some("prod")).as_deref(),
+            Some("custom")
+        );
+        assert_eq!(resolve(None, None, None), None);
+    }
+
+    #[tokio::test]
+    async fn log_listing_favorites_in_range_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_favorites";
+        let entries = [
+            (
+                "M31",
+                true,
+                Utc.with_ymd_and_hms(2023, 12, 20, 21, 0, 0).unwrap(),
+            ),
+            (
+                "M42",
+                true,
+                Utc.with_ymd_and_hms(2024, 2, 10, 21, 0, 0).unwrap(),
+            ),
+            (
+                "M1",
+                false,
+                Utc.with_ymd_and_hms(2024, 3, 5, 21, 0, 0).unwrap(),
+            ),
+            (
+                "M13",
+                true,
+                Utc.with_ymd_and_hms(2024, 6, 1, 23, 0, 0).unwrap(),
+            ),
+            (
+                "M57",
+                true,
+                Utc.with_ymd_and_hms(2025, 1, 3, 22, 0, 0).unwrap(),
+            ),
+        ];
+        let mut ids = Vec::new();
+        for (object_name, favorite, date) in entries {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                favorite,
+                ..Default::default()
+            };
+            let mut log = Log::from_observation_request(&req);
+            log.date = date;
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            favorites_only: true,
+            from: Some(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()),
+            to: Some(Utc.with_ymd_and_hms(2024, 12, 31, 23, 59, 59).unwrap()),
+            ..Default::default()
+        };
+        let cursor = log_listing(&client, &list_req).await.unwrap();
+        let mut names: Vec<String> = cursor
+            .try_collect::<Vec<Log>>()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|log| log.observation.object_name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["M13", "M42"]);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn user_id_serialization_test() {
+        let user_id = UserId::from(USER_ID);
+        assert_eq!(user_id.to_string(), USER_ID);
+
+        let list_req = GetListRequest {
+            user_id: user_id.clone(),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_list_filter(&list_req),
+            doc! {"user_id": USER_ID, "deleted_at": Bson::Null}
+        );
+
+        let req = ObservationRequest {
+            user_id,
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let stored = mongodb::bson::to_document(&log).unwrap();
+        assert_eq!(stored.get_str("user_id").unwrap(), USER_ID);
+        let json = serde_json::to_value(&req).unwrap();
+        assert_eq!(json["userId"], USER_ID);
+    }
+
+    #[tokio::test]
+    async fn observation_leaderboard_test() {
+        let client = mongodb_connection().await.unwrap();
+        let mut inserted = Vec::new();
+        for (user_id, object_name) in [
+            ("fake_id_leader_b", "M31"),
+            ("fake_id_leader_a", "M31"),
+            ("fake_id_leader_a", "M42"),
+            ("fake_id_leader_a", "M1"),
+        ] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+            inserted.push((user_id, log_id));
+        }
+        let prefs_coll: Collection<UserPrefs> = client
+            .database(&CONFIG.database_name)
+            .collection(&CONFIG.prefs_collection);
+        let prefs = UserPrefs {
+            user_id: "fake_id_leader_a".into(),
+            experience_level: Some(ExperienceLevel::Advanced),
+        };
+        prefs_coll.insert_one(&prefs).await.unwrap();
+
+        // other users may be in the collection too
+        let leaderboard = observation_leaderboard(&client, 1000).await.unwrap();
+        let position = |user_id: &str| {
+            leaderboard
+                .iter()
+                .position(|entry| entry.user_id.as_str() == user_id)
+                .unwrap()
+        };
+        let (a, b) = (position("fake_id_leader_a"), position("fake_id_leader_b"));
+        assert!(a < b);
+        assert_eq!(leaderboard[a].count, 3);
+        assert_eq!(leaderboard[b].count, 1);
+        assert!(leaderboard
+            .windows(2)
+            .all(|pair| pair[0].count >= pair[1].count));
+        assert_eq!(
+            leaderboard[a].experience_level,
+            Some(ExperienceLevel::Advanced)
+        );
+        assert_eq!(leaderboard[b].experience_level, None);
+
+        prefs_coll
+            .delete_many(doc! {"user_id": "fake_id_leader_a"})
+            .await
+            .unwrap();
+
+        //delete
+        for (user_id, log_id) in inserted {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn render_notes_html_test() {
+        let html = render_notes_html("**beautiful** even with:\n\n- bad seeing\n- full moon\n");
+        assert!(html.contains("<strong>beautiful</strong>"));
+        assert!(html.contains("<ul>"));
+        assert!(html.contains("<li>bad seeing</li>"));
+        assert!(html.contains("<li>full moon</li>"));
+
+        let html = render_notes_html("dust lanes <script>alert('xss')</script> visible");
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("alert"));
+        assert!(html.contains("dust lanes"));
+
+        let html = render_notes_html("[sketch](javascript:alert(1))");
+        assert!(!html.contains("javascript:"));
+    }
+
+    #[tokio::test]
+    async fn log_delete_returning_test() {
+        let client = mongodb_connection().await.unwrap();
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            object_location: "Andromeda".to_string(),
+            equipment: "Dobson 254/1250".to_string(),
+            eyepiece: "25mm".to_string(),
+            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+
+        let delete_req = DeleteLogRequest {
+            user_id: USER_ID.into(),
+            log_id,
+        };
+        let deleted = log_delete_returning(&client, &delete_req)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(deleted._id, Some(log_id));
+        assert_eq!(deleted.user_id, log.user_id);
+        assert_eq!(deleted.observation.object_name, log.observation.object_name);
+        assert_eq!(deleted.observation.notes, log.observation.notes);
+
+        let get_req = GetLogRequest {
+            user_id: USER_ID.into(),
+            log_id,
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        assert!(log_retrieval(&client, &get_req).await.unwrap().is_none());
+        let deleted = log_delete_returning(&client, &delete_req).await.unwrap();
+        assert!(deleted.is_none());
+    }
+
+    #[tokio::test]
+    async fn client_options_parsed_once_test() {
+        for _ in 0..3 {
+            mongodb_connection().await.unwrap();
+        }
+        assert_eq!(CLIENT_OPTIONS_PARSES.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn app_name_test() {
+        assert_eq!(resolve_app_name(None), "nightlog");
+        assert_eq!(resolve_app_name(Some(" ".to_string())), "nightlog");
+
+        let mut client_options = ClientOptions::default();
+        let timeout = std::time::Duration::from_millis(600);
+        configure_client_options(&mut client_options, &resolve_app_name(None), timeout);
+        assert_eq!(client_options.app_name.as_deref(), Some("nightlog"));
+        assert_eq!(client_options.server_selection_timeout, Some(timeout));
+
+        let app_name = resolve_app_name(Some("nightlog-staging".to_string()));
+        let url_timeout = std::time::Duration::from_millis(300);
+        client_options.server_selection_timeout = Some(url_timeout);
+        configure_client_options(&mut client_options, &app_name, timeout);
+        assert_eq!(client_options.app_name.as_deref(), Some("nightlog-staging"));
+        assert_eq!(client_options.server_selection_timeout, Some(url_timeout));
+        assert!(client_options.server_api.is_some());
+
+        let vars = |app_name: Option<&str>| {
+            let mut vars = vec![
+                ("DATABASE_URL", "mongodb://localhost"),
+                ("DATABASE_NAME", "nightlog"),
+                ("DATABASE_COLLECTION", "logs"),
+            ];
+            vars.extend(app_name.map(|app_name| ("NIGHTLOG_APP_NAME", app_name)));
+            vars.into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let config = Config::from_vars(vars(Some("nightlog-test")));
+        assert_eq!(config.app_name, "nightlog-test");
+        let config = Config::from_vars(vars(None));
+        assert_eq!(config.app_name, "nightlog");
+        assert_eq!(config.operation_timeout.as_millis(), 2_500);
+    }
+
+    #[tokio::test]
+    async fn log_with_coordinates_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_coordinates";
+        let mut ids = Vec::new();
+        for (object_name, right_ascension, declination) in [
+            ("M31", Some(10.6847), Some(41.2690)),
+            ("M42", None, None),
+            ("M1", Some(83.6330), None),
+        ] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                right_ascension,
+                declination,
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let logs = log_with_coordinates(&client, &user_id.into())
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].observation.object_name, "M31");
+        assert_eq!(logs[0].observation.right_ascension, Some(10.6847));
+        assert_eq!(logs[0].observation.declination, Some(41.2690));
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn changed_fields_test() {
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
+        };
+        let old = Observation::from_request(&req);
+        let mut new = old.clone();
+        assert!(old.changed_fields(&new).is_empty());
+        new.notes = "dust lanes visible".to_string();
+        new.declination = Some(41.2690);
+        assert_eq!(old.changed_fields(&new), vec!["notes", "declination"]);
+    }
+
+    #[tokio::test]
+    async fn log_replacement_audit_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_audit";
+        let req = ObservationRequest {
+            user_id: user_id.into(),
+            object_name: "M31".to_string(),
+            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+
+        log.observation.notes = "dust lanes visible".to_string();
+        let rep = replace_log(&log, &client, true).await.unwrap();
+        assert_eq!(rep.modified_count, 1);
+
+        let audit_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<AuditEntry>(&CONFIG.audit_collection);
+        let entries = audit_coll
+            .find(doc! {"log_id": log_id})
+            .await
+            .unwrap()
+            .try_collect::<Vec<AuditEntry>>()
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].changed_fields, vec!["notes"]);
+        assert_eq!(entries[0].user_id, UserId::from(user_id));
+
+        //delete
+        audit_coll
+            .delete_many(doc! {"log_id": log_id})
+            .await
+            .unwrap();
+        let delete_req = DeleteLogRequest {
+            user_id: user_id.into(),
+            log_id,
+        };
+        log_deletion(&client, &delete_req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_distinct_sites_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_sites";
+        let mut ids = Vec::new();
+        for (object_name, site) in [
+            ("M31", Some((45.4642, 9.19))),
+            ("M42", Some((46.0037, 8.9511))),
+            ("M1", Some((45.4642, 9.19))),
+            ("M13", None),
+        ] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                site_latitude: site.map(|(latitude, _)| latitude),
+                site_longitude: site.map(|(_, longitude)| longitude),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let sites = log_distinct_sites(&client, &user_id.into()).await.unwrap();
+        assert_eq!(sites, vec![(45.4642, 9.19), (46.0037, 8.9511)]);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn list_limit_test() {
+        let list_req = |limit| GetListRequest {
+            user_id: USER_ID.into(),
+            limit,
+            ..Default::default()
+        };
+        assert_eq!(list_limit(&list_req(None)), MAX_LIST_RESULTS);
+        assert_eq!(list_limit(&list_req(Some(20))), 20);
+        assert_eq!(list_limit(&list_req(Some(0))), MAX_LIST_RESULTS);
+        assert_eq!(list_limit(&list_req(Some(-5))), MAX_LIST_RESULTS);
+        assert_eq!(list_limit(&list_req(Some(5000))), MAX_LIST_RESULTS);
+    }
+
+    #[tokio::test]
+    async fn log_listing_capped_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_capped";
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let req = ObservationRequest {
+            user_id: user_id.into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let logs: Vec<Log> = (0..MAX_LIST_RESULTS + 5)
+            .map(|_| Log::from_observation_request(&req))
+            .collect();
+        my_coll.insert_many(logs).await.unwrap();
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            ..Default::default()
+        };
+        let listing = log_listing_capped(&client, &list_req).await.unwrap();
+        assert_eq!(listing.logs.len() as i64, MAX_LIST_RESULTS);
+        assert!(listing.capped);
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            skip: Some(10),
+            ..Default::default()
+        };
+        let listing = log_listing_capped(&client, &list_req).await.unwrap();
+        assert_eq!(listing.logs.len() as i64, MAX_LIST_RESULTS - 5);
+        assert!(!listing.capped);
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            limit: Some(10),
+            ..Default::default()
+        };
+        let listing = log_listing_capped(&client, &list_req).await.unwrap();
+        assert_eq!(listing.logs.len(), 10);
+        assert!(!listing.capped);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn observation_request_validate_test() {
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            declination: Some(41.27),
+            ..Default::default()
+        };
+        assert_eq!(req.validate(), Ok(()));
+
+        let req = ObservationRequest {
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(req.validate().unwrap_err()[0].field, "userId");
+
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "  ".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(req.validate().unwrap_err()[0].field, "objectName");
+
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            declination: Some(120.0),
+            ..Default::default()
+        };
+        assert_eq!(req.validate().unwrap_err()[0].field, "declination");
+
+        // all the invalid fields come back together
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: String::new(),
+            rating: Some(7),
+            declination: Some(-95.0),
+            ..Default::default()
+        };
+        let fields: Vec<String> = req
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|error| error.field)
+            .collect();
+        assert_eq!(fields, ["objectName", "rating", "declination"]);
+    }
+
+    #[tokio::test]
+    async fn log_import_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_import";
+        let entry = |object_name: &str, declination: Option<f64>| ObservationRequest {
+            object_name: object_name.to_string(),
+            declination,
+            ..Default::default()
+        };
+        let req = ImportRequest {
+            user_id: user_id.into(),
+            logs: vec![
+                entry("M31", Some(41.27)),
+                entry("", None),
+                entry("M42", Some(-5.39)),
+                entry("M13", Some(120.0)),
+            ],
+        };
+        let outcomes = log_import(&client, &req).await.unwrap();
+        assert_eq!(outcomes.len(), 4);
+        assert!(matches!(outcomes[0], ImportOutcome::Inserted(_)));
+        assert_eq!(
+            outcomes[1],
+            ImportOutcome::Invalid(vec![FieldError::new("objectName", "must not be empty")])
+        );
+        assert!(matches!(outcomes[2], ImportOutcome::Inserted(_)));
+        assert!(
+            matches!(&outcomes[3], ImportOutcome::Invalid(errors) if errors[0].field == "declination")
+        );
+
+        let ImportOutcome::Inserted(log_id) = outcomes[2] else {
+            unreachable!()
+        };
+        let get_req = GetLogRequest {
+            user_id: user_id.into(),
+            log_id,
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        let log = log_retrieval(&client, &get_req).await.unwrap().unwrap();
+        assert_eq!(log.observation.object_name, "M42");
+
+        //delete
+        client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection)
+            .delete_many(doc! {"user_id": user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_listing_max_seeing_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_seeing";
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = [Some(1), Some(3), None]
+            .into_iter()
+            .map(|seeing| {
+                Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.into(),
+                    object_name: "M31".to_string(),
+                    seeing,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            max_seeing: Some(2),
+            ..Default::default()
+        };
+        let found: Vec<Log> = log_listing(&client, &list_req)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0]._id, logs[0]._id);
+        assert_eq!(found[0].observation.seeing, Some(1));
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_date_bounds_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_bounds".into();
+        assert_eq!(log_date_bounds(&client, &user_id).await.unwrap(), None);
+
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let dates = [
+            Utc.with_ymd_and_hms(2024, 3, 10, 22, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2023, 8, 12, 23, 30, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 11, 2, 20, 15, 0).unwrap(),
+        ];
+        let observation = Observation::new(
+            "M31".to_string(),
+            "Andromeda".to_string(),
+            "Dobson 254/1250".to_string(),
+            "25mm".to_string(),
+            String::new(),
+        );
+        let logs: Vec<Log> = dates
+            .iter()
+            .map(|date| {
+                let mut log = Log::new(&user_id, &observation);
+                log.date = *date;
+                log
+            })
+            .collect();
+        my_coll.insert_many(logs).await.unwrap();
+
+        let bounds = log_date_bounds(&client, &user_id).await.unwrap();
+        assert_eq!(bounds, Some((dates[1], dates[2])));
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn replace_log_request_test() {
+        let req: ReplaceLogRequest = serde_json::from_value(serde_json::json!({
+            "logId": {"$oid": "65f0c0ffee0000000000beef"},
+            "userId": USER_ID,
+            "date": "2024-03-10T21:00:00Z",
+            "objectName": "M31",
+            "objectLocation": "Andromeda",
+            "equipment": "Dobson 254/1250",
+            "eyepiece": "25mm",
+            "notes": "dust lane visible",
+            "constellation": "andromeda",
+            "shareToken": "forged",
+            "deletedAt": null,
+        }))
+        .unwrap();
+        let log = req.to_log();
+        assert_eq!(log._id, Some(req.log_id));
+        assert_eq!(
+            log.date,
+            Utc.with_ymd_and_hms(2024, 3, 10, 21, 0, 0).unwrap()
+        );
+        assert_eq!(log.observation.constellation.as_deref(), Some("Andromeda"));
+        assert_eq!(log.observation.magnification, Some(50.0));
+        assert_eq!(log.share_token, None);
+    }
+
+    #[tokio::test]
+    async fn log_replacement_server_fields_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_server_fields";
+        let req = ObservationRequest {
+            user_id: user_id.into(),
+            object_name: "M31".to_string(),
+            source: Some("stellarium".to_string()),
+            attachments: vec!["sketch.png".to_string()],
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        log.share_token = Some("token".to_string());
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+
+        let mut forged = log.clone();
+        forged.observation.notes = "dust lane visible".to_string();
+        forged.share_token = Some("forged".to_string());
+        forged.deleted_at = Some(Utc::now());
+        forged.source = None;
+        forged.attachments = Vec::new();
+        log_replacement(&forged, &client).await.unwrap();
+
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let stored = my_coll
+            .find_one(doc! {"_id": log_id})
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.observation.notes, "dust lane visible");
+        assert_eq!(stored.share_token.as_deref(), Some("token"));
+        assert_eq!(stored.deleted_at, None);
+        assert_eq!(stored.source.as_deref(), Some("stellarium"));
+        assert_eq!(stored.attachments, vec!["sketch.png"]);
+
+        //delete
+        my_coll.delete_one(doc! {"_id": log_id}).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_replacement_ownership_test() {
+        let client = mongodb_connection().await.unwrap();
+        let req = ObservationRequest {
+            user_id: "fake_id_owner".into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+
+        let mut stolen = log.clone();
+        stolen.user_id = "fake_id_intruder".into();
+        stolen.observation.notes = "not mine".to_string();
+        let err = log_replacement(&stolen, &client).await.unwrap_err();
+        assert!(matches!(err, NightlogError::Forbidden));
+        assert_eq!(err.status_code(), 403);
+
+        let mut missing = log.clone();
+        missing._id = Some(ObjectId::new());
+        let err = log_replacement(&missing, &client).await.unwrap_err();
+        assert!(matches!(err, NightlogError::NotFound));
+        assert_eq!(err.status_code(), 404);
+
+        //delete
+        let del_req = DeleteLogRequest {
+            user_id: "fake_id_owner".into(),
+            log_id,
+        };
+        log_deletion(&client, &del_req).await.unwrap();
+    }
+
+    #[test]
+    fn logs_to_csv_test() {
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            notes: "faint, \"dusty\" lane\nvisible".to_string(),
+            seeing: Some(2),
+            ..Default::default()
+        };
+        let logs = vec![Log::from_observation_request(&req)];
+        let csv = logs_to_csv(&logs, true);
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().starts_with("id,date,object_name"));
+        assert!(csv.contains(&format!("{},", logs[0]._id.unwrap().to_hex())));
+        assert!(csv.contains(",\"faint, \"\"dusty\"\" lane\nvisible\","));
+        assert!(csv.ends_with(",,,,2\n"));
+        assert!(!logs_to_csv(&logs, false).starts_with("id,"));
+    }
+
+    #[tokio::test]
+    async fn log_export_csv_chunks_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_export";
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = (0..5)
+            .map(|n| {
+                let mut log = Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.into(),
+                    object_name: format!("M{}", n + 1),
+                    ..Default::default()
+                });
+                log.date = Utc.with_ymd_and_hms(2024, 1, 1 + n, 22, 0, 0).unwrap();
+                log
+            })
+            .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let export_req = ExportRequest {
+            user_id: user_id.into(),
+            limit: Some(3),
+            ..Default::default()
+        };
+        let first = log_export_csv(&client, &export_req).await.unwrap();
+        assert_eq!(first.next, Some(3));
+        let export_req = ExportRequest {
+            user_id: user_id.into(),
+            skip: first.next,
+            limit: Some(3),
+        };
+        let second = log_export_csv(&client, &export_req).await.unwrap();
+        assert_eq!(second.next, None);
+
+        let csv = first.csv + &second.csv;
+        assert_eq!(csv.matches("id,date,").count(), 1);
+        let names: Vec<&str> = csv
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(2).unwrap())
+            .collect();
+        assert_eq!(names, vec!["M5", "M4", "M3", "M2", "M1"]);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn magnification_test() {
+        assert_eq!(magnification("Dobson 254/1250", "25mm"), Some(50.0));
+        assert_eq!(
+            magnification("Refractor 80/400", " 6.5 mm"),
+            Some(400.0 / 6.5)
+        );
+        assert_eq!(magnification("binoculars", "25mm"), None);
+        assert_eq!(magnification("Dobson 254/1250", "zoom"), None);
+    }
+
+    #[test]
+    fn moon_illumination_test() {
+        // new moon of the 2024-04-08 eclipse, full moon of 2024-04-23
+        let new_moon = Utc.with_ymd_and_hms(2024, 4, 8, 18, 21, 0).unwrap();
+        let full_moon = Utc.with_ymd_and_hms(2024, 4, 23, 23, 49, 0).unwrap();
+        assert!(moon_illumination(&new_moon) < 0.03);
+        assert!(moon_illumination(&full_moon) > 0.97);
+
+        // a log follows the date it's moved to
+        let mut log = Log::from_observation_request(&ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        });
+        log.set_date(full_moon);
+        assert_eq!(
+            log.observation.moon_illumination,
+            Some(moon_illumination(&full_moon))
+        );
+    }
+
+    #[tokio::test]
+    async fn recompute_derived_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_derived";
+        let req = ObservationRequest {
+            user_id: user_id.into(),
+            object_name: "M31".to_string(),
+            equipment: "Dobson 254/1250".to_string(),
+            eyepiece: "25mm".to_string(),
+            constellation: Some("andromeda".to_string()),
+            right_ascension: Some(10.6847),
+            declination: Some(41.2690),
+            site_latitude: Some(45.0),
+            site_longitude: Some(9.19),
+            ..Default::default()
+        };
+        // whole seconds, so that the stored date derives the same fields
+        let date = Utc.with_ymd_and_hms(2024, 1, 15, 21, 0, 0).unwrap();
+        let mut log = Log::from_observation_request(&req);
+        log.set_date(date);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+        let mut given = Log::from_observation_request(&ObservationRequest {
+            altitude_deg: Some(30.0),
+            ..req
+        });
+        given.set_date(date);
+        let given_id = log_insertion(&given, &client).await.unwrap().unwrap();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        // a stale computed altitude gets fixed as well
+        my_coll
+            .update_one(
+                doc! {"_id": log_id},
+                doc! {"$set": {"observation.magnification": 1.0, "observation.altitude_deg": 1.0}},
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            recompute_derived(&client, Some(&user_id.into()))
+                .await
+                .unwrap(),
+            1
+        );
+        let get_req = GetLogRequest {
+            user_id: user_id.into(),
+            log_id,
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        let fixed = log_retrieval(&client, &get_req).await.unwrap().unwrap();
+        assert_eq!(fixed.observation.magnification, Some(50.0));
+        assert_eq!(
+            fixed.observation.constellation.as_deref(),
+            Some("Andromeda")
+        );
+        assert_eq!(
+            fixed.observation.altitude_deg,
+            Some(compute_altitude(10.6847, 41.2690, 45.0, 9.19, date))
+        );
+        let get_req = GetLogRequest {
+            log_id: given_id,
+            ..get_req
+        };
+        let kept = log_retrieval(&client, &get_req).await.unwrap().unwrap();
+        assert_eq!(kept.observation.altitude_deg, Some(30.0));
+        // nothing left to fix on a second run
+        assert_eq!(
+            recompute_derived(&client, Some(&user_id.into()))
+                .await
+                .unwrap(),
+            0
+        );
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn observation_request_strict_test() {
+        let payload = serde_json::json!({
+            "userId": USER_ID,
+            "objectName": "M31",
+            "objectLocation": "Andromeda",
+            "equipment": "Dobson 254/1250",
+            "eyepiece": "25mm",
+            "notes": "",
+            "constelation": "Andromeda"
+        });
+        let err = ObservationRequest::from_json(payload.clone(), true).unwrap_err();
+        assert_eq!(err, FieldError::new("constelation", "unknown field"));
+
+        let req = ObservationRequest::from_json(payload, false).unwrap();
+        assert_eq!(req.object_name, "M31");
+        assert_eq!(req.constellation, None);
+    }
+
+    #[test]
+    fn observation_request_strict_known_fields_test() {
+        let payload = serde_json::json!({
+            "userId": USER_ID,
+            "objectName": "M31",
+            "objectLocation": "Andromeda",
+            "equipment": "Dobson 254/1250",
+            "eyepiece": "25mm",
+            "notes": "",
+            "seeing": 2
+        });
+        let req = ObservationRequest::from_json(payload, true).unwrap();
+        assert_eq!(req.object_name, "M31");
+        assert_eq!(req.seeing, Some(2));
+
+        // every field of the request is known, and nothing else
+        let sample = serde_json::to_value(ObservationRequest::default()).unwrap();
+        let mut fields: Vec<&str> = sample
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect();
+        let mut known = OBSERVATION_REQUEST_FIELDS.to_vec();
+        fields.sort_unstable();
+        known.sort_unstable();
+        assert_eq!(fields, known);
+    }
+
+    #[tokio::test]
+    async fn log_retrieval_many_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_many".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = ["M31", "M42", "M13"]
+            .into_iter()
+            .map(|object_name| {
+                Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.clone(),
+                    object_name: object_name.to_string(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let ids = [logs[2]._id.unwrap(), logs[0]._id.unwrap()];
+        let found = log_retrieval_many(&client, &user_id, &ids).await.unwrap();
+        let names: Vec<&str> = found
+            .iter()
+            .map(|log| log.observation.object_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["M13", "M31"]);
+
+        let other_user: UserId = "fake_id_other".into();
+        let found = log_retrieval_many(&client, &other_user, &ids)
+            .await
+            .unwrap();
+        assert!(found.is_empty());
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn estimated_magnitude_validate_test() {
+        let req = |estimated_magnitude| ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "Mira".to_string(),
+            estimated_magnitude,
+            ..Default::default()
+        };
+        assert_eq!(req(Some(3.4)).validate(), Ok(()));
+        assert_eq!(req(None).validate(), Ok(()));
+        assert_eq!(
+            req(Some(35.0)).validate().unwrap_err()[0].field,
+            "estimatedMagnitude"
+        );
+        assert_eq!(
+            req(Some(-8.0)).validate().unwrap_err()[0].field,
+            "estimatedMagnitude"
+        );
+    }
+
+    #[test]
+    fn comparison_stars_serde_test() {
+        let req: ObservationRequest = serde_json::from_value(serde_json::json!({
+            "userId": USER_ID,
+            "objectName": "Mira",
+            "objectLocation": "Cetus",
+            "equipment": "binoculars 10x50",
+            "eyepiece": "",
+            "notes": "",
+            "estimatedMagnitude": 3.4,
+            "comparisonStars": ["alpha Cet", "gamma Cet"]
+        }))
+        .unwrap();
+        let log = Log::from_observation_request(&req);
+        let document = mongodb::bson::to_document(&log).unwrap();
+        let stored: Log = mongodb::bson::from_document(document).unwrap();
+        assert_eq!(stored.observation.estimated_magnitude, Some(3.4));
+        assert_eq!(
+            stored.observation.comparison_stars,
+            vec!["alpha Cet", "gamma Cet"]
+        );
+
+        // logs saved before the field existed have no comparison stars
+        let mut document = mongodb::bson::to_document(&log).unwrap();
+        document
+            .get_document_mut("observation")
+            .unwrap()
+            .remove("comparison_stars");
+        let stored: Log = mongodb::bson::from_document(document).unwrap();
+        assert!(stored.observation.comparison_stars.is_empty());
+    }
+
+    #[tokio::test]
+    async fn archive_old_logs_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_archive".into();
+        let database = client.database(&CONFIG.database_name);
+        let my_coll = database.collection::<Log>(&CONFIG.database_collection);
+        let archive_coll = database.collection::<Log>(&CONFIG.archive_collection);
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let mut old = Log::from_observation_request(&req);
+        old.date = Utc.with_ymd_and_hms(1990, 6, 1, 22, 0, 0).unwrap();
+        let recent = Log::from_observation_request(&req);
+        my_coll.insert_many([&old, &recent]).await.unwrap();
+        // as a run that failed before deleting it has left it
+        archive_coll.insert_one(&old).await.unwrap();
+
+        let cutoff = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
+        assert!(archive_old_logs(&client, cutoff).await.unwrap() >= 1);
+        archive_old_logs(&client, cutoff).await.unwrap();
+
+        let left: Vec<Log> = my_coll
+            .find(doc! {"user_id": &user_id})
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0]._id, recent._id);
+        let archived = archive_coll
+            .find_one(doc! {"_id": old._id})
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(archived.user_id, user_id);
+        let copies = archive_coll
+            .count_documents(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+        assert_eq!(copies, 1);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+        archive_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn build_list_filter_test() {
+        let from = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let to = Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap();
+
+        let list_req = GetListRequest {
+            user_id: USER_ID.into(),
+            constellation: Some("ursa major".to_string()),
+            eyepiece: Some("25mm".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_list_filter(&list_req),
+            doc! {
+                "user_id": USER_ID,
+                "deleted_at": Bson::Null,
+                "observation.constellation": "Ursa Major",
+                "observation.eyepiece": "25mm",
+            }
+        );
+
+        let list_req = GetListRequest {
+            user_id: USER_ID.into(),
+            favorites_only: true,
+            max_seeing: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_list_filter(&list_req),
+            doc! {
+                "user_id": USER_ID,
+                "deleted_at": Bson::Null,
+                "favorite": true,
+                "observation.seeing": {"$lte": 2},
+            }
+        );
+
+        let list_req = GetListRequest {
+            user_id: USER_ID.into(),
+            from: Some(from),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_list_filter(&list_req),
+            doc! {"user_id": USER_ID, "deleted_at": Bson::Null, "date": {"$gte": date_bson(&from)}}
+        );
+
+        let list_req = GetListRequest {
+            user_id: USER_ID.into(),
+            from: Some(from),
+            to: Some(to),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_list_filter(&list_req),
+            doc! {"user_id": USER_ID, "deleted_at": Bson::Null, "date": {"$gte": date_bson(&from), "$lte": date_bson(&to)}}
+        );
+
+        // an unknown constellation is matched as given
+        let list_req = GetListRequest {
+            user_id: USER_ID.into(),
+            constellation: Some("Vulcan".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_list_filter(&list_req),
+            doc! {"user_id": USER_ID, "deleted_at": Bson::Null, "observation.constellation": "Vulcan"}
+        );
+    }
+
+    #[tokio::test]
+    async fn log_equipment_stats_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_equipment".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = [
+            "Dobson 254/1250",
+            "Refractor 80/400",
+            "Dobson 254/1250",
+            "Dobson 254/1250",
+        ]
+        .into_iter()
+        .map(|equipment| {
+            Log::from_observation_request(&ObservationRequest {
+                user_id: user_id.clone(),
+                object_name: "M31".to_string(),
+                equipment: equipment.to_string(),
+                ..Default::default()
+            })
+        })
+        .collect();
+        my_coll.insert_many(logs).await.unwrap();
+
+        let stats = log_equipment_stats(&client, &user_id).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ("Dobson 254/1250".to_string(), 3),
+                ("Refractor 80/400".to_string(), 1)
+            ]
+        );
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rename_user_test() {
+        let client = mongodb_connection().await.unwrap();
+        let old_id: UserId = "fake_id_old_name".into();
+        let new_id: UserId = "fake_id_new_name".into();
+        let database = client.database(&CONFIG.database_name);
+        let my_coll = database.collection::<Log>(&CONFIG.database_collection);
+        let prefs_coll = database.collection::<Document>(&CONFIG.prefs_collection);
+        let profiles_coll = database.collection::<Document>(&CONFIG.profiles_collection);
+        let req = ObservationRequest {
+            user_id: old_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        my_coll
+            .insert_many([
+                Log::from_observation_request(&req),
+                Log::from_observation_request(&req),
+            ])
+            .await
+            .unwrap();
+        prefs_coll
+            .insert_one(doc! {"user_id": &old_id, "units": "metric"})
+            .await
+            .unwrap();
+        profiles_coll
+            .insert_one(doc! {"user_id": &old_id, "equipment": "Dobson 254/1250"})
+            .await
+            .unwrap();
+
+        let report = rename_user(&client, &old_id, &new_id).await.unwrap();
+        assert_eq!(
+            report,
+            RenameReport {
+                logs: 2,
+                prefs: 1,
+                profiles: 1
+            }
+        );
+        let filter = doc! {"user_id": &old_id};
+        assert_eq!(my_coll.count_documents(filter.clone()).await.unwrap(), 0);
+        let filter = doc! {"user_id": &new_id};
+        assert_eq!(my_coll.count_documents(filter.clone()).await.unwrap(), 2);
+        assert_eq!(prefs_coll.count_documents(filter.clone()).await.unwrap(), 1);
+        assert_eq!(
+            profiles_coll.count_documents(filter.clone()).await.unwrap(),
+            1
+        );
+
+        //delete
+        my_coll.delete_many(filter.clone()).await.unwrap();
+        prefs_coll.delete_many(filter.clone()).await.unwrap();
+        profiles_coll.delete_many(filter).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_share_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_share".into();
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+
+        let token = log_create_share(&client, log_id, &user_id).await.unwrap();
+        assert_eq!(token.len(), 32);
+        let shared = log_get_by_share(&client, &token).await.unwrap().unwrap();
+        assert_eq!(shared._id, Some(log_id));
+        assert!(log_get_by_share(&client, "wrong_token")
+            .await
+            .unwrap()
+            .is_none());
+
+        let other_user: UserId = "fake_id_other".into();
+        let err = log_create_share(&client, log_id, &other_user)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NightlogError::NotFound));
+
+        // a deleted log can't get a new link
+        let del_req = DeleteLogRequest { user_id, log_id };
+        log_soft_delete(&client, &del_req).await.unwrap();
+        let err = log_create_share(&client, log_id, &del_req.user_id)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NightlogError::NotFound));
+
+        //delete
+        log_deletion(&client, &del_req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_count_soft_deleted_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_soft_delete".into();
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            duration_minutes: Some(30),
+            ..Default::default()
+        };
+        let kept = Log::from_observation_request(&req);
+        let deleted = Log::from_observation_request(&req);
+        log_insertion(&kept, &client).await.unwrap();
+        let log_id = log_insertion(&deleted, &client).await.unwrap().unwrap();
+
+        let del_req = DeleteLogRequest {
+            user_id: user_id.clone(),
+            log_id,
+        };
+        let res = log_soft_delete(&client, &del_req).await.unwrap();
+        assert_eq!(res.modified_count, 1);
+
+        assert_eq!(log_count(&client, &user_id, false).await.unwrap(), 1);
+        assert_eq!(log_count(&client, &user_id, true).await.unwrap(), 2);
+        assert_eq!(
+            log_total_observing_minutes(&client, &user_id)
+                .await
+                .unwrap(),
+            30
+        );
+        let get_req = GetLogRequest {
+            user_id: user_id.clone(),
+            log_id,
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        assert!(log_retrieval(&client, &get_req).await.unwrap().is_none());
+
+        //delete
+        client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection)
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_soft_delete_returning_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_soft_delete_returning".into();
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+
+        let del_req = DeleteLogRequest {
+            user_id: user_id.clone(),
+            log_id,
+        };
+        let deleted = log_soft_delete_returning(&client, &del_req)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(deleted.observation.object_name, "M31");
+        // a log is only deleted once
+        assert!(log_soft_delete_returning(&client, &del_req)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(log_count(&client, &user_id, true).await.unwrap(), 1);
+
+        //delete
+        log_deletion(&client, &del_req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_nightly_summary_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_nightly".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        // the first two are the same night, before and after midnight
+        let logs: Vec<Log> = [
+            ("M31", Utc.with_ymd_and_hms(2024, 9, 3, 22, 0, 0).unwrap()),
+            ("M42", Utc.with_ymd_and_hms(2024, 9, 4, 2, 30, 0).unwrap()),
+            ("M13", Utc.with_ymd_and_hms(2024, 9, 7, 21, 0, 0).unwrap()),
+        ]
+        .into_iter()
+        .map(|(object_name, date)| {
+            let mut log = Log::from_observation_request(&ObservationRequest {
+                user_id: user_id.clone(),
+                object_name: object_name.to_string(),
+                ..Default::default()
+            });
+            log.date = date;
+            log
+        })
+        .collect();
+        my_coll.insert_many(logs).await.unwrap();
+
+        let summaries = log_nightly_summary(&client, &user_id).await.unwrap();
+        assert_eq!(
+            summaries,
+            vec![
+                NightSummary {
+                    date: NaiveDate::from_ymd_opt(2024, 9, 3).unwrap(),
+                    objects: vec!["M31".to_string(), "M42".to_string()],
+                },
+                NightSummary {
+                    date: NaiveDate::from_ymd_opt(2024, 9, 7).unwrap(),
+                    objects: vec!["M13".to_string()],
+                },
+            ]
+        );
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
 
     #[test]
-    fn log_creation_test() {
+    fn build_search_filter_test() {
+        let search_req = SearchLogRequest {
+            user_id: USER_ID.into(),
+            query: " M1 (crab) ".to_string(),
+            ..Default::default()
+        };
+        let filter = build_search_filter(&search_req);
+        assert_eq!(filter.get_str("user_id").unwrap(), USER_ID);
+        assert!(!filter.contains_key("date"));
+        let conditions = filter.get_array("$or").unwrap();
+        let Some(Bson::Document(condition)) = conditions.first() else {
+            panic!("missing object name condition");
+        };
+        let Some(Bson::RegularExpression(regex)) = condition.get("observation.object_name") else {
+            panic!("object name isn't matched with a regex");
+        };
+        assert_eq!(regex.pattern, "M1 \\(crab\\)");
+        assert_eq!(regex.options, "i");
+    }
+
+    #[tokio::test]
+    async fn log_search_date_range_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_search".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = [
+            (
+                "M42",
+                "great nebula",
+                Utc.with_ymd_and_hms(2024, 1, 10, 21, 0, 0).unwrap(),
+            ),
+            (
+                "M57",
+                "ring Nebula",
+                Utc.with_ymd_and_hms(2024, 7, 2, 23, 0, 0).unwrap(),
+            ),
+            (
+                "M13",
+                "globular",
+                Utc.with_ymd_and_hms(2024, 7, 3, 23, 0, 0).unwrap(),
+            ),
+        ]
+        .into_iter()
+        .map(|(object_name, notes, date)| {
+            let mut log = Log::from_observation_request(&ObservationRequest {
+                user_id: user_id.clone(),
+                object_name: object_name.to_string(),
+                notes: notes.to_string(),
+                ..Default::default()
+            });
+            log.date = date;
+            log
+        })
+        .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let search_req = SearchLogRequest {
+            user_id: user_id.clone(),
+            query: "nebula".to_string(),
+            from: Some(Utc.with_ymd_and_hms(2024, 7, 1, 0, 0, 0).unwrap()),
+            to: Some(Utc.with_ymd_and_hms(2024, 7, 31, 0, 0, 0).unwrap()),
+        };
+        let found = log_search(&client, &search_req).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0]._id, logs[1]._id);
+
+        let search_req = SearchLogRequest {
+            user_id: "fake_id_other".into(),
+            query: "nebula".to_string(),
+            ..Default::default()
+        };
+        assert!(log_search(&client, &search_req).await.unwrap().is_empty());
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn public_log_test() {
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        log.share_token = Some("token".to_string());
+        log.deleted_at = Some(Utc::now());
+        let json = serde_json::to_value(log.to_public()).unwrap();
+        let fields = json.as_object().unwrap();
+        assert!(!fields.contains_key("share_token"));
+        assert!(!fields.contains_key("deleted_at"));
+        assert_eq!(json["observation"]["objectName"], "M31");
+        assert_eq!(
+            json["date"],
+            log.date.to_rfc3339_opts(SecondsFormat::Millis, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn log_history_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_history".into();
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+
+        log.observation.notes = "dust lanes visible".to_string();
+        replace_log(&log, &client, true).await.unwrap();
+        log.observation.eyepiece = "10mm".to_string();
+        replace_log(&log, &client, true).await.unwrap();
+
+        let history = log_history(&client, log_id, &user_id).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].changed_fields, vec!["notes"]);
+        assert!(history[1].changed_fields.contains(&"eyepiece".to_string()));
+        assert!(history[0].timestamp <= history[1].timestamp);
+        let other_user: UserId = "fake_id_other".into();
+        assert!(log_history(&client, log_id, &other_user)
+            .await
+            .unwrap()
+            .is_empty());
+
+        //delete
+        client
+            .database(&CONFIG.database_name)
+            .collection::<AuditEntry>(&CONFIG.audit_collection)
+            .delete_many(doc! {"log_id": log_id})
+            .await
+            .unwrap();
+        let delete_req = DeleteLogRequest { user_id, log_id };
+        log_deletion(&client, &delete_req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn validate_coordinates_report_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_coordinates_report".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = [(10.68, 41.27), (83.82, 120.0)]
+            .into_iter()
+            .map(|(right_ascension, declination)| {
+                Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.clone(),
+                    object_name: "M31".to_string(),
+                    right_ascension: Some(right_ascension),
+                    declination: Some(declination),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let report = validate_coordinates_report(&client, &user_id)
+            .await
+            .unwrap();
+        assert_eq!(report, vec![logs[1]._id.unwrap()]);
+
+        let repaired = repair_invalid_coordinates(&client, &user_id).await.unwrap();
+        assert_eq!(repaired, report);
+        let fixed = my_coll
+            .find_one(doc! {"_id": logs[1]._id})
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fixed.observation.declination, None);
+        assert_eq!(fixed.observation.right_ascension, Some(83.82));
+        assert!(validate_coordinates_report(&client, &user_id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_weekly_counts_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_weekly".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        // 2024-12-30 is a monday, the first day of the 2025 ISO year
+        let logs: Vec<Log> = [
+            Utc.with_ymd_and_hms(2024, 12, 27, 21, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 12, 28, 22, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 12, 30, 20, 0, 0).unwrap(),
+        ]
+        .into_iter()
+        .map(|date| {
+            let mut log = Log::from_observation_request(&ObservationRequest {
+                user_id: user_id.clone(),
+                object_name: "M31".to_string(),
+                ..Default::default()
+            });
+            log.date = date;
+            log
+        })
+        .collect();
+        my_coll.insert_many(logs).await.unwrap();
+
+        let weeks = log_weekly_counts(&client, &user_id).await.unwrap();
+        assert_eq!(
+            weeks,
+            vec![("2024-W52".to_string(), 2), ("2025-W01".to_string(), 1)]
+        );
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_with_neighbors_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_neighbors".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = ["M31", "M42", "M13"]
+            .into_iter()
+            .enumerate()
+            .map(|(day, object_name)| {
+                let mut log = Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.clone(),
+                    object_name: object_name.to_string(),
+                    ..Default::default()
+                });
+                log.date = Utc
+                    .with_ymd_and_hms(2024, 5, 1 + day as u32, 22, 0, 0)
+                    .unwrap();
+                log
+            })
+            .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+        let ids: Vec<ObjectId> = logs.iter().map(|log| log._id.unwrap()).collect();
+
+        let middle = log_with_neighbors(&client, ids[1], &user_id).await.unwrap();
+        assert_eq!(middle.log.observation.object_name, "M42");
+        assert_eq!(
+            middle.previous,
+            Some(Neighbor {
+                id: ids[0],
+                object_name: "M31".to_string()
+            })
+        );
+        assert_eq!(
+            middle.next,
+            Some(Neighbor {
+                id: ids[2],
+                object_name: "M13".to_string()
+            })
+        );
+
+        let first = log_with_neighbors(&client, ids[0], &user_id).await.unwrap();
+        assert_eq!(first.previous, None);
+        assert_eq!(first.next.unwrap().id, ids[1]);
+        let last = log_with_neighbors(&client, ids[2], &user_id).await.unwrap();
+        assert_eq!(last.next, None);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_add_tag_bulk_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_bulk_tag".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = ["Albireo", "M31", "Albireo"]
+            .into_iter()
+            .map(|object_name| {
+                Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.clone(),
+                    object_name: object_name.to_string(),
+                    tags: vec!["summer".to_string()],
+                    ..Default::default()
+                })
+            })
+            .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let filter = TagFilter {
+            object_name: Some("Albireo".to_string()),
+            ..Default::default()
+        };
+        let modified = log_add_tag_bulk(&client, &user_id, filter, "double star".to_string())
+            .await
+            .unwrap();
+        assert_eq!(modified, 2);
+
+        let tagged: Vec<Log> = my_coll
+            .find(doc! {"user_id": &user_id, "tags": "double star"})
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(tagged.len(), 2);
+        for log in tagged {
+            assert_eq!(log.observation.object_name, "Albireo");
+            assert_eq!(log.tags, vec!["summer", "double star"]);
+        }
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn log_to_nexstar_test() {
+        let log = |object_name: &str, coordinates: Option<(f64, f64)>| {
+            Log::from_observation_request(&ObservationRequest {
+                user_id: USER_ID.into(),
+                object_name: object_name.to_string(),
+                right_ascension: coordinates.map(|(ra, _)| ra),
+                declination: coordinates.map(|(_, dec)| dec),
+                ..Default::default()
+            })
+        };
+        let logs = vec![
+            log("M31", Some((10.684583, 41.269167))),
+            log("Jupiter", None),
+            log("M42", Some((83.8221, -5.391111))),
+        ];
+        assert_eq!(
+            log_to_nexstar(&logs),
+            "# name,ra,dec\nM31,00:42:44,+41:16:09\nM42,05:35:17,-05:23:28\n"
+        );
+    }
+
+    #[test]
+    fn features_from_vars_test() {
+        let vars = [
+            ("NIGHTLOG_FEATURE_SOFT_DELETE", "true"),
+            ("NIGHTLOG_FEATURE_AUDIT", "1"),
+            ("NIGHTLOG_FEATURE_STRICT_REQUESTS", "no"),
+            ("NIGHTLOG_FEATURE_UNKNOWN", "true"),
+            ("NIGHTLOG_FEATURE_REJECT_FUTURE_DATES", "1"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        assert_eq!(
+            Features::from_vars(vars),
+            Features {
+                audit: true,
+                strict_requests: false,
+                soft_delete: true,
+                reject_future_dates: true,
+            }
+        );
+
+        let legacy = [("STRICT_REQUESTS", "true"), ("AUDIT_ENABLED", "false")]
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        assert_eq!(
+            Features::from_vars(legacy),
+            Features {
+                strict_requests: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn log_moonphase_distribution_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_moonphase".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = [Some(0.01), Some(0.02), Some(0.5), Some(0.99), None]
+            .into_iter()
+            .map(|illumination| {
+                let mut log = Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.clone(),
+                    object_name: "M31".to_string(),
+                    ..Default::default()
+                });
+                log.observation.moon_illumination = illumination;
+                log
+            })
+            .collect();
+        my_coll.insert_many(logs).await.unwrap();
+
+        let distribution = log_moonphase_distribution(&client, &user_id).await.unwrap();
+        assert_eq!(
+            distribution,
+            vec![
+                ("new".to_string(), 2),
+                ("crescent".to_string(), 0),
+                ("quarter".to_string(), 1),
+                ("gibbous".to_string(), 0),
+                ("full".to_string(), 1),
+            ]
+        );
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_append_note_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_append_note".into();
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            notes: "core bright".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+
+        let res = log_append_note(&client, log_id, &user_id, "M110 visible later")
+            .await
+            .unwrap();
+        assert_eq!(res.modified_count, 1);
+        let get_req = GetLogRequest {
+            user_id: user_id.clone(),
+            log_id,
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
+        };
+        let updated = log_retrieval(&client, &get_req).await.unwrap().unwrap();
+        assert_eq!(
+            updated.observation.notes,
+            "core bright\n\nM110 visible later"
+        );
+
+        let other_user: UserId = "fake_id_other".into();
+        let res = log_append_note(&client, log_id, &other_user, "not mine")
+            .await
+            .unwrap();
+        assert_eq!(res.matched_count, 0);
+
+        //delete
+        let del_req = DeleteLogRequest { user_id, log_id };
+        log_deletion(&client, &del_req).await.unwrap();
+    }
+
+    #[test]
+    fn millisecond_date_round_trip_test() {
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        log.date = DateTime::parse_from_rfc3339("2024-08-12T23:41:07.250Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        // the driver's own serializer, which stores a bson date
+        let raw = mongodb::bson::to_raw_document_buf(&log).unwrap();
+        let stored = raw.get("date").unwrap().unwrap();
+        assert_eq!(
+            stored.as_datetime().unwrap().timestamp_millis(),
+            log.date.timestamp_millis()
+        );
+        let from_db: Log = mongodb::bson::from_slice(raw.as_bytes()).unwrap();
+        assert_eq!(from_db.date, log.date);
+        // `to_document` is human readable, and must store bson dates all the same
+        let document = mongodb::bson::to_document(&log).unwrap();
+        assert_eq!(
+            document.get_datetime("date").unwrap().timestamp_millis(),
+            log.date.timestamp_millis()
+        );
+        assert!(document.get_datetime("updated_at").is_ok());
+        let from_document: Log = mongodb::bson::from_document(document).unwrap();
+        assert_eq!(from_document.date, log.date);
+
+        let json = serde_json::to_value(log.to_public()).unwrap();
+        assert_eq!(json["date"], "2024-08-12T23:41:07.250Z");
+        // the backups hold the stored documents, bson dates and all
+        let stored_json = serde_json::to_string(&log).unwrap();
+        let from_backup: Log = serde_json::from_str(&stored_json).unwrap();
+        assert_eq!(from_backup.date, log.date);
+    }
+
+    #[tokio::test]
+    async fn migrate_integer_dates_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_migrate_dates";
+        let my_coll: Collection<Document> = client
+            .database(&CONFIG.database_name)
+            .collection(&CONFIG.database_collection);
+        let legacy = doc! {
+            "user_id": user_id,
+            "date": 1700000000_i64,
+            "observation": {"object_name": "M31", "object_location": "Andromeda",
+                "equipment": "", "eyepiece": "", "notes": ""},
+            "updated_at": 1700000000_i64,
+        };
+        let inserted = my_coll.insert_one(legacy).await.unwrap();
+        let log_id = inserted.inserted_id.as_object_id().unwrap();
+
+        assert!(migrate_integer_dates(&client).await.unwrap() >= 1);
+        let stored = my_coll
+            .find_one(doc! {"_id": log_id})
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            stored.get_datetime("date").unwrap().timestamp_millis(),
+            1_700_000_000_000
+        );
+        assert_eq!(
+            stored
+                .get_datetime("updated_at")
+                .unwrap()
+                .timestamp_millis(),
+            1_700_000_000_000
+        );
+        let log: Log = mongodb::bson::from_document(stored).unwrap();
+        assert_eq!(log.date.timestamp(), 1700000000);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn find_catalog_object_test() {
+        let object = find_catalog_object("m 31").unwrap();
+        assert_eq!(object.name, "M31");
+        assert_eq!(object.constellation, "Andromeda");
+        assert!(find_catalog_object("M3").is_none());
+        assert!(CATALOG
+            .iter()
+            .all(|object| canonical_constellation(object.constellation).is_some()));
+    }
+
+    #[tokio::test]
+    async fn detect_name_coordinate_conflicts_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_name_conflicts";
+        let mut ids = Vec::new();
+        for (object_name, right_ascension, declination) in [
+            // M31 pointing at the Orion nebula
+            ("M31", Some(83.822), Some(-5.391)),
+            ("M42", Some(83.8221), Some(-5.3911)),
+            ("NGC 7000", Some(83.822), Some(-5.391)),
+            ("M1", None, None),
+        ] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                right_ascension,
+                declination,
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let conflicts = detect_name_coordinate_conflicts(&client, &user_id.into())
+            .await
+            .unwrap();
+        assert_eq!(conflicts, vec![ids[0]]);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn log_delete_user_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_delete_user".into();
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        for _ in 0..7 {
+            let log = Log::from_observation_request(&req);
+            log_insertion(&log, &client).await.unwrap();
+        }
+
+        // three batches of three, the last one short
+        let deleted = delete_user_in_batches(&client, &user_id, 3).await.unwrap();
+        assert_eq!(deleted, 7);
+        assert_eq!(log_count(&client, &user_id, true).await.unwrap(), 0);
+        assert_eq!(log_delete_user(&client, &user_id).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn observation_schema_test() {
+        let schema = observation_schema();
+        let fields = schema["fields"].as_array().unwrap();
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|field| field["name"] == name)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(
+            field("objectName"),
+            serde_json::json!({"name": "objectName", "type": "string", "required": true})
+        );
+        assert_eq!(field("userId")["required"], false);
+        assert_eq!(field("durationMinutes")["type"], "integer");
+        assert_eq!(field("favorite")["type"], "boolean");
+        assert_eq!(field("tags")["type"], "array");
+        let declination = field("declination");
+        assert_eq!(declination["type"], "number");
+        assert_eq!(declination["required"], false);
+        assert_eq!(declination["min"], -90.0);
+        assert_eq!(declination["max"], 90.0);
+    }
+
+    #[tokio::test]
+    async fn log_missing_coordinates_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_missing_coordinates";
+        let mut ids = Vec::new();
+        for (object_name, right_ascension, declination) in [
+            ("M31", Some(10.6847), Some(41.2690)),
+            ("M1", Some(83.6330), None),
+        ] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                right_ascension,
+                declination,
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let logs = log_missing_coordinates(&client, &user_id.into())
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].observation.object_name, "M1");
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn rating_validate_test() {
+        let req = |rating| ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            rating,
+            ..Default::default()
+        };
+        assert_eq!(req(Some(5)).validate(), Ok(()));
+        assert_eq!(req(None).validate(), Ok(()));
+        assert_eq!(
+            req(Some(0)).validate(),
+            Err(vec![FieldError::new("rating", "must be between 1 and 5")])
+        );
+    }
+
+    #[tokio::test]
+    async fn log_avg_rating_by_object_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_avg_rating";
+        for (object_name, rating) in [
+            ("M31", Some(4)),
+            ("M31", Some(5)),
+            ("M31", None),
+            ("M42", Some(3)),
+        ] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                rating,
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            log_insertion(&log, &client).await.unwrap();
+        }
+
+        let ratings = log_avg_rating_by_object(&client, &user_id.into())
+            .await
+            .unwrap();
+        assert_eq!(
+            ratings,
+            vec![("M31".to_string(), 4.5), ("M42".to_string(), 3.0)]
+        );
+
+        //delete
+        let my_coll: Collection<Log> = client
+            .database(&CONFIG.database_name)
+            .collection(&CONFIG.database_collection);
+        my_coll
+            .delete_many(doc! {"user_id": user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn observation_status_test() {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "object_name": "M31",
+            "object_location": "Andromeda",
+            "equipment": "",
+            "eyepiece": "",
+            "notes": ""
+        }))
+        .unwrap();
+        assert_eq!(observation.status, ObservationStatus::Completed);
+
+        let list_req = GetListRequest {
+            user_id: USER_ID.into(),
+            status: Some(ObservationStatus::Planned),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_list_filter(&list_req),
+            doc! {"user_id": USER_ID, "deleted_at": Bson::Null, "observation.status": "planned"}
+        );
+        let list_req = GetListRequest {
+            user_id: USER_ID.into(),
+            status: Some(ObservationStatus::Completed),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_list_filter(&list_req),
+            doc! {"user_id": USER_ID, "deleted_at": Bson::Null, "observation.status": {"$ne": "planned"}}
+        );
+    }
+
+    #[tokio::test]
+    async fn planned_observation_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_planned";
+        let mut ids = Vec::new();
+        for (object_name, status) in [
+            ("M51", ObservationStatus::Planned),
+            ("M31", ObservationStatus::Completed),
+        ] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                status,
+                right_ascension: Some(202.4696),
+                declination: Some(47.1952),
+                site_latitude: Some(45.4642),
+                site_longitude: Some(9.19),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let planned_req = GetListRequest {
+            user_id: user_id.into(),
+            status: Some(ObservationStatus::Planned),
+            ..Default::default()
+        };
+        let planned = log_listing_capped(&client, &planned_req).await.unwrap();
+        assert_eq!(planned.logs.len(), 1);
+        assert_eq!(planned.logs[0].observation.object_name, "M51");
+
+        let observed_on = Utc.with_ymd_and_hms(2024, 4, 12, 21, 30, 0).unwrap();
+        let modified = log_mark_completed(&client, ids[0], &user_id.into(), observed_on)
+            .await
+            .unwrap();
+        assert_eq!(modified, 1);
+        let planned = log_listing_capped(&client, &planned_req).await.unwrap();
+        assert!(planned.logs.is_empty());
+        let completed_req = GetListRequest {
+            user_id: user_id.into(),
+            status: Some(ObservationStatus::Completed),
+            ..Default::default()
+        };
+        let completed = log_listing_capped(&client, &completed_req).await.unwrap();
+        assert_eq!(completed.logs.len(), 2);
+        let m51 = completed
+            .logs
+            .iter()
+            .find(|log| log._id == Some(ids[0]))
+            .unwrap();
+        assert_eq!(m51.date, observed_on);
+        assert_eq!(
+            m51.observation.moon_illumination,
+            Some(moon_illumination(&observed_on))
+        );
+        assert_eq!(
+            m51.observation.altitude_deg,
+            Some(compute_altitude(
+                202.4696,
+                47.1952,
+                45.4642,
+                9.19,
+                observed_on
+            ))
+        );
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn backup_log_test() {
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        log.share_token = Some("token".to_string());
+        log.deleted_at = Some(Utc.with_ymd_and_hms(2024, 3, 10, 21, 0, 0).unwrap());
+        let line = serde_json::to_value(log.to_backup()).unwrap();
+        assert_eq!(line["_id"], log._id.unwrap().to_hex());
+        assert_eq!(line["deletedAt"], "2024-03-10T21:00:00.000Z");
+        assert_eq!(line["observation"]["objectName"], "M31");
+        assert!(line["date"].is_string());
+        assert!(line.get("shareToken").is_none());
+    }
+
+    #[tokio::test]
+    async fn backup_user_logs_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_backup".into();
+        let mut ids = Vec::new();
+        for object_name in ["M31", "M42", "M45"] {
+            let req = ObservationRequest {
+                user_id: user_id.clone(),
+                object_name: object_name.to_string(),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let mut buffer = Vec::new();
+        let written = backup_user_logs(&client, &user_id, &mut buffer)
+            .await
+            .unwrap();
+        let count = log_count(&client, &user_id, true).await.unwrap();
+        assert_eq!(written, count);
+        let lines: Vec<&str> = std::str::from_utf8(&buffer).unwrap().lines().collect();
+        assert_eq!(lines.len() as u64, count);
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["userId"], user_id.as_str());
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.clone(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn log_replacement_user_change_test() {
+        let client = mongodb_connection().await.unwrap();
+        let owner: UserId = "fake_id_keeps_owner".into();
         let req = ObservationRequest {
-            user_id: USER_ID.to_string(),
+            user_id: owner.clone(),
             object_name: "M31".to_string(),
-            object_location: "Andromeda".to_string(),
-            equipment: "Dobson 254/1250".to_string(),
-            eyepiece: "25mm".to_string(),
-            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
         };
         let log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
 
-        assert_eq!(req.user_id, log.user_id);
-        assert_eq!(req.object_name, log.observation.object_name);
-        assert_eq!(req.object_location, log.observation.object_location);
-        assert_eq!(req.equipment, log.observation.equipment);
-        assert_eq!(req.eyepiece, log.observation.eyepiece);
-        assert_eq!(req.notes, log.observation.notes);
+        let mut moved = log.clone();
+        moved.user_id = "fake_id_new_owner".into();
+        moved.observation.object_name = "M33".to_string();
+        let err = log_replacement(&moved, &client).await.unwrap_err();
+        assert!(matches!(err, NightlogError::Forbidden));
+
+        let my_coll: Collection<Log> = client
+            .database(&CONFIG.database_name)
+            .collection(&CONFIG.database_collection);
+        let stored = my_coll
+            .find_one(doc! {"_id": log_id})
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.user_id, owner);
+        assert_eq!(stored.observation.object_name, "M31");
+
+        //delete
+        let del_req = DeleteLogRequest {
+            user_id: owner,
+            log_id,
+        };
+        log_deletion(&client, &del_req).await.unwrap();
     }
 
     #[tokio::test]
-    async fn log_insertion_test() {
+    async fn log_on_this_day_test() {
         let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_on_this_day";
+        let mut ids = Vec::new();
+        for (object_name, year, day) in [("M31", 2022, 12), ("M45", 2024, 12), ("M42", 2024, 13)] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                ..Default::default()
+            };
+            let mut log = Log::from_observation_request(&req);
+            log.date = Utc.with_ymd_and_hms(year, 8, day, 23, 0, 0).unwrap();
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let logs = log_on_this_day(&client, &user_id.into(), 8, 12)
+            .await
+            .unwrap();
+        let names: Vec<&str> = logs
+            .iter()
+            .map(|log| log.observation.object_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["M45", "M31"]);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn camel_case_api_json_test() {
         let req = ObservationRequest {
-            user_id: USER_ID.to_string(),
+            user_id: USER_ID.into(),
             object_name: "M31".to_string(),
-            object_location: "Andromeda".to_string(),
-            equipment: "Dobson 254/1250".to_string(),
-            eyepiece: "25mm".to_string(),
-            notes: "beautiful, even with a bad seeing".to_string(),
+            site_latitude: Some(45.5),
+            ..Default::default()
         };
         let log = Log::from_observation_request(&req);
-        let res;
-        match log_insertion(&log, &client).await {
-            Ok(option_id) => res = option_id,
-            Err(e) => {
-                println!("res is error: {:?}", e);
-                panic!();
+
+        let body = to_api_json(&log.to_public(), JsonStyle::default()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["observation"]["objectName"], "M31");
+        assert_eq!(json["observation"]["siteLatitude"], 45.5);
+        assert_eq!(json["userId"], USER_ID);
+        assert!(json["_id"]["$oid"].is_string());
+        assert!(json.get("user_id").is_none());
+        assert!(!body.contains('\n'));
+
+        // requests are named the same way
+        let parsed: ObservationRequest = serde_json::from_value(serde_json::json!({
+            "userId": USER_ID,
+            "objectName": "M31",
+            "objectLocation": "Andromeda",
+            "equipment": "",
+            "eyepiece": "",
+            "notes": "",
+            "siteLatitude": 45.5
+        }))
+        .unwrap();
+        assert_eq!(parsed.site_latitude, Some(45.5));
+
+        let pretty = JsonStyle { pretty: true };
+        let body = to_api_json(&log.to_public(), pretty).unwrap();
+        assert!(body.contains("\n  \"observation\": {\n    "));
+
+        let stored = mongodb::bson::to_document(&log).unwrap();
+        let observation = stored.get_document("observation").unwrap();
+        assert_eq!(observation.get_str("object_name").unwrap(), "M31");
+        assert!(!observation.contains_key("objectName"));
+    }
+
+    #[test]
+    fn estimate_limiting_magnitude_test() {
+        let dark = estimate_limiting_magnitude(2, 0.0);
+        assert!((dark - 7.3).abs() < 1e-9);
+        let bright = estimate_limiting_magnitude(8, 75.0);
+        assert!((bright - 2.8).abs() < 1e-9);
+        assert_eq!(estimate_limiting_magnitude(9, 0.0), 4.0);
+    }
+
+    #[test]
+    fn limiting_magnitude_from_request_test() {
+        let req = |limiting_magnitude, cloud_cover_pct| ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            bortle: Some(2),
+            cloud_cover_pct,
+            limiting_magnitude,
+            ..Default::default()
+        };
+        let estimated = Observation::from_request(&req(None, Some(0.0)));
+        assert_eq!(
+            estimated.limiting_magnitude,
+            Some(estimate_limiting_magnitude(2, 0.0))
+        );
+        // the observer's value wins over the estimate
+        let entered = Observation::from_request(&req(Some(6.2), Some(0.0)));
+        assert_eq!(entered.limiting_magnitude, Some(6.2));
+        // no estimate without the cloud cover
+        let partial = Observation::from_request(&req(None, None));
+        assert_eq!(partial.limiting_magnitude, None);
+    }
+
+    #[test]
+    fn user_prefs_serde_test() {
+        let prefs: UserPrefs = mongodb::bson::from_document(
+            doc! {"user_id": USER_ID, "units": "metric", "experience_level": "beginner"},
+        )
+        .unwrap();
+        assert_eq!(prefs.experience_level, Some(ExperienceLevel::Beginner));
+        let prefs: UserPrefs = mongodb::bson::from_document(doc! {"user_id": USER_ID}).unwrap();
+        assert_eq!(prefs.experience_level, None);
+    }
+
+    #[test]
+    fn date_gaps_test() {
+        let day = |day| Utc.with_ymd_and_hms(2024, 3, day, 22, 0, 0).unwrap();
+        assert!(date_gaps(&[], Duration::days(3)).is_empty());
+        assert_eq!(
+            date_gaps(&[day(10), day(1), day(2)], Duration::days(3)),
+            vec![(day(2), day(10))]
+        );
+        // exactly the threshold is not a gap
+        assert!(date_gaps(&[day(1), day(4)], Duration::days(3)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn find_date_gaps_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_date_gaps";
+        let day = |day| Utc.with_ymd_and_hms(2024, 3, day, 22, 0, 0).unwrap();
+        let mut ids = Vec::new();
+        for date in [day(1), day(2), day(10)] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: "M31".to_string(),
+                ..Default::default()
+            };
+            let mut log = Log::from_observation_request(&req);
+            log.date = date;
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let gaps = find_date_gaps(&client, &user_id.into(), 3).await.unwrap();
+        assert_eq!(gaps, vec![(day(2), day(10))]);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn with_retry_test() {
+        // nothing listens there, so no server is ever found
+        let unreachable =
+            Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+                .await
+                .unwrap();
+        let attempts = std::cell::Cell::new(0);
+        let res = with_retry(|| {
+            attempts.set(attempts.get() + 1);
+            let failing = attempts.get() == 1;
+            let unreachable = &unreachable;
+            async move {
+                if failing {
+                    unreachable.list_database_names().await?;
+                }
+                Ok::<_, mongodb::error::Error>(42)
             }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 42);
+        assert_eq!(attempts.get(), 2);
+
+        let attempts = std::cell::Cell::new(0);
+        let res = with_retry(|| {
+            attempts.set(attempts.get() + 1);
+            unreachable.list_database_names()
+        })
+        .await;
+        assert!(res.unwrap_err().is_server_selection());
+        assert_eq!(attempts.get(), 3);
+
+        // the other errors are not a failover
+        let attempts = std::cell::Cell::new(0);
+        let res = with_retry(|| {
+            attempts.set(attempts.get() + 1);
+            async { Err::<(), _>(NightlogError::NotFound) }
+        })
+        .await;
+        assert!(matches!(res, Err(NightlogError::NotFound)));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn min_limiting_magnitude_listing_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_limiting_magnitude";
+        let mut ids = Vec::new();
+        for limiting_magnitude in [Some(4.0), Some(5.5), None] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: "M31".to_string(),
+                limiting_magnitude,
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            min_limiting_magnitude: Some(5.0),
+            ..Default::default()
         };
-        let res = res.unwrap();
-        let get_req = GetLogRequest {
-            user_id: USER_ID.to_string(),
-            log_id: res.clone(),
+        let listing = log_listing_capped(&client, &list_req).await.unwrap();
+        assert_eq!(listing.logs.len(), 1);
+        assert_eq!(listing.logs[0]._id, Some(ids[1]));
+        assert_eq!(listing.logs[0].observation.limiting_magnitude, Some(5.5));
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn session_report_test() {
+        let log = |object_name: &str, equipment: &str, notes: &str, hour| {
+            let req = ObservationRequest {
+                user_id: USER_ID.into(),
+                object_name: object_name.to_string(),
+                equipment: equipment.to_string(),
+                notes: notes.to_string(),
+                ..Default::default()
+            };
+            let mut log = Log::from_observation_request(&req);
+            log.date = Utc.with_ymd_and_hms(2024, 3, 10, 12, 0, 0).unwrap() + Duration::hours(hour);
+            log
         };
-        let saved = log_retrieval(&client, &get_req).await.unwrap();
-        assert!(saved.is_some());
-        let delete_req = DeleteLogRequest {
-            user_id: USER_ID.to_string(),
-            log_id: res,
+        // the second log is after midnight, still the same night
+        let logs = vec![
+            log("M42", "Dobson 254/1250", "", 14),
+            log("M31", "binoculars 10x50", "low on the horizon", 9),
+            log("M42", "Dobson 254/1250", "trapezium resolved", 10),
+        ];
+        let report = session_report(&logs);
+        assert_eq!(report.night, NaiveDate::from_ymd_opt(2024, 3, 10));
+        assert_eq!(report.object_count, 2);
+        assert_eq!(report.objects, vec!["M31", "M42"]);
+        assert_eq!(
+            report.equipment,
+            vec!["binoculars 10x50", "Dobson 254/1250"]
+        );
+        assert_eq!(
+            report.notes,
+            "M31: low on the horizon\n\nM42: trapezium resolved"
+        );
+
+        let empty = session_report(&[]);
+        assert_eq!(empty.night, None);
+        assert_eq!(empty.object_count, 0);
+    }
+
+    #[tokio::test]
+    async fn log_night_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_night";
+        let mut ids = Vec::new();
+        for (object_name, day, hour) in [("M42", 10, 22), ("M31", 11, 2), ("M45", 11, 22)] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                ..Default::default()
+            };
+            let mut log = Log::from_observation_request(&req);
+            log.date = Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap();
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let night = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap();
+        let logs = log_night(&client, &user_id.into(), night).await.unwrap();
+        let names: Vec<&str> = logs
+            .iter()
+            .map(|log| log.observation.object_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["M42", "M31"]);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn log_listing_ids_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_listing_ids";
+        let mut ids = Vec::new();
+        for (object_name, minutes) in [("M31", 2), ("M42", 1)] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                ..Default::default()
+            };
+            let mut log = Log::from_observation_request(&req);
+            log.date -= Duration::minutes(minutes);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            ids_only: true,
+            ..Default::default()
         };
-        let deleted = log_deletion(&client, &delete_req).await.unwrap();
-        assert_eq!(deleted.deleted_count, 1);
+        // most recent first, like the listing
+        let listed = log_listing_ids(&client, &list_req).await.unwrap();
+        assert_eq!(listed, vec![ids[1], ids[0]]);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
     }
 
     #[tokio::test]
-    async fn log_deletion_test() {
+    async fn log_dashboard_stats_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_dashboard".into();
+        assert_eq!(
+            log_dashboard_stats(&client, &user_id).await.unwrap(),
+            DashboardStats::default()
+        );
+
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let seeds = [
+            ("M42", Utc.with_ymd_and_hms(2024, 1, 5, 21, 0, 0).unwrap()),
+            ("M31", Utc.with_ymd_and_hms(2023, 11, 12, 22, 0, 0).unwrap()),
+            ("M31", Utc.with_ymd_and_hms(2024, 1, 20, 20, 30, 0).unwrap()),
+            ("M45", Utc.with_ymd_and_hms(2024, 2, 2, 19, 45, 0).unwrap()),
+        ];
+        let logs: Vec<Log> = seeds
+            .iter()
+            .map(|(object_name, date)| {
+                let observation = Observation::new(
+                    object_name.to_string(),
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                );
+                let mut log = Log::new(&user_id, &observation);
+                log.date = *date;
+                log
+            })
+            .collect();
+        my_coll.insert_many(logs).await.unwrap();
+
+        let stats = log_dashboard_stats(&client, &user_id).await.unwrap();
+        assert_eq!(
+            stats.total,
+            log_count(&client, &user_id, false).await.unwrap()
+        );
+        let bounds = log_date_bounds(&client, &user_id).await.unwrap();
+        assert_eq!(stats.first.zip(stats.last), bounds);
+        assert_eq!(stats.top_object.as_deref(), Some("M31"));
+        assert_eq!(
+            stats.monthly_counts,
+            vec![
+                ("2023-11".to_string(), 1),
+                ("2024-01".to_string(), 2),
+                ("2024-02".to_string(), 1),
+            ]
+        );
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn resolve_alias_test() {
+        assert_eq!(resolve_alias("NGC 224"), "M31");
+        assert_eq!(resolve_alias("ngc224"), "M31");
+        assert_eq!(resolve_alias("Orion Nebula"), "M42");
+        assert_eq!(resolve_alias("m 57"), "M57");
+        assert_eq!(resolve_alias("Jupiter"), "Jupiter");
+    }
+
+    #[tokio::test]
+    async fn alias_grouping_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_aliases";
+        for (object_name, rating) in [("NGC 224", 4), ("M31", 5)] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                rating: Some(rating),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            log_insertion(&log, &client).await.unwrap();
+        }
+
+        let ratings = log_avg_rating_by_object(&client, &user_id.into())
+            .await
+            .unwrap();
+        assert_eq!(ratings, vec![("M31".to_string(), 4.5)]);
+        // the logs keep the name they were saved with
+        let my_coll: Collection<Log> = client
+            .database(&CONFIG.database_name)
+            .collection(&CONFIG.database_collection);
+        let filter = doc! {"user_id": user_id, "observation.object_name": "NGC 224"};
+        assert_eq!(my_coll.count_documents(filter).await.unwrap(), 1);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn find_future_dated_test() {
         let client = mongodb_connection().await.unwrap();
         let req = ObservationRequest {
-            user_id: USER_ID.to_string(),
+            user_id: "fake_id_future".into(),
             object_name: "M31".to_string(),
-            object_location: "Andromeda".to_string(),
-            equipment: "Dobson 254/1250".to_string(),
-            eyepiece: "25mm".to_string(),
-            notes: "beautiful, even with a bad seeing".to_string(),
+            ..Default::default()
         };
-        let log = Log::from_observation_request(&req);
-        let res;
-        match log_insertion(&log, &client).await {
-            Ok(option_id) => res = option_id,
-            Err(e) => {
-                println!("res is error: {:?}", e);
-                panic!();
-            }
+        let past = Log::from_observation_request(&req);
+        let mut future = Log::from_observation_request(&req);
+        future.date += Duration::days(2);
+        assert!(future.is_future_dated());
+        assert!(future.check_date(false).is_ok());
+        assert_eq!(future.check_date(true).unwrap_err().field, "date");
+        assert!(past.check_date(true).is_ok());
+
+        log_insertion(&past, &client).await.unwrap();
+        let future_id = log_insertion(&future, &client).await.unwrap().unwrap();
+        let flagged = find_future_dated(&client, &req.user_id).await.unwrap();
+        assert_eq!(flagged, vec![future_id]);
+
+        //delete
+        let my_coll: Collection<Log> = client
+            .database(&CONFIG.database_name)
+            .collection(&CONFIG.database_collection);
+        my_coll
+            .delete_many(doc! {"user_id": &req.user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn local_sidereal_time_test() {
+        // Meeus, example 12.b: 1987 April 10 at 19h21m UT is 8h34m57.0896s at Greenwich
+        let meeus = Utc.with_ymd_and_hms(1987, 4, 10, 19, 21, 0).unwrap();
+        assert!((local_sidereal_time(meeus, 0.0) - 128.737_873).abs() < 1e-4);
+        // Washington, 77.065 degrees west
+        assert!((local_sidereal_time(meeus, -77.065) - 51.672_873).abs() < 1e-4);
+        // wraps past 360 degrees
+        let midnight = Utc.with_ymd_and_hms(1987, 4, 10, 0, 0, 0).unwrap();
+        assert!((local_sidereal_time(midnight, 180.0) - 17.693_195).abs() < 1e-4);
+
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
         };
-        let res = res.unwrap();
-        let get_req = GetLogRequest {
-            user_id: USER_ID.to_string(),
-            log_id: res.clone(),
+        let mut log = Log::from_observation_request(&req);
+        assert_eq!(log.to_public().local_sidereal_time, None);
+        log.date = meeus;
+        log.observation.site_longitude = Some(-77.065);
+        let lst = log.to_public().local_sidereal_time.unwrap();
+        assert!((lst - 51.672_873).abs() < 1e-4);
+    }
+
+    #[tokio::test]
+    async fn purge_older_than_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_purge".into();
+        let future = Utc::now() + Duration::days(1);
+        let error = purge_older_than(&client, &user_id, future)
+            .await
+            .unwrap_err();
+        assert_eq!(error.status_code(), 400);
+
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
         };
-        let saved = log_retrieval(&client, &get_req).await.unwrap();
-        assert!(saved.is_some());
-        let delete_req = DeleteLogRequest {
-            user_id: USER_ID.to_string(),
-            log_id: res,
+        let recent = Log::from_observation_request(&req);
+        let mut old = Log::from_observation_request(&req);
+        old.date = Utc.with_ymd_and_hms(2015, 6, 1, 22, 0, 0).unwrap();
+        my_coll.insert_many([&old, &recent]).await.unwrap();
+        // another user's old log stays
+        let mut other = old.clone();
+        other._id = Some(ObjectId::new());
+        other.user_id = "fake_id_purge_other".into();
+        my_coll.insert_one(&other).await.unwrap();
+
+        let cutoff = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let purged = purge_older_than(&client, &user_id, cutoff).await.unwrap();
+        assert_eq!(purged, 1);
+        let left: Vec<Log> = my_coll
+            .find(doc! {"user_id": &user_id})
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0]._id, recent._id);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": {"$in": [&user_id, &other.user_id]}})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn julian_date_test() {
+        // the J2000.0 epoch
+        let j2000 = Utc.with_ymd_and_hms(2000, 1, 1, 12, 0, 0).unwrap();
+        assert!((julian_date(j2000) - 2_451_545.0).abs() < 1e-6);
+        // Meeus, example 7.a: the launch of Sputnik 1, 1957 October 4.81
+        let sputnik = Utc.with_ymd_and_hms(1957, 10, 4, 19, 26, 24).unwrap();
+        assert!((julian_date(sputnik) - 2_436_116.31).abs() < 1e-6);
+
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        log.date = j2000;
+        assert_eq!(log.to_public().julian_date, julian_date(j2000));
+    }
+
+    #[tokio::test]
+    async fn log_source_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_source";
+        let mut ids = Vec::new();
+        for source in [Some("skysafari"), None] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: "M31".to_string(),
+                source: source.map(str::to_owned),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            source: Some("skysafari".to_string()),
+            ..Default::default()
+        };
+        let logs: Vec<Log> = log_listing(&client, &list_req)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]._id, Some(ids[0]));
+        assert_eq!(logs[0].source.as_deref(), Some("skysafari"));
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            source: Some(DEFAULT_SOURCE.to_string()),
+            ..Default::default()
+        };
+        let logs: Vec<Log> = log_listing(&client, &list_req)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]._id, Some(ids[1]));
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn log_sky_quality_extremes_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_sky_quality".into();
+        assert!(log_sky_quality_extremes(&client, &user_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = [Some(3.0), None, Some(6.0), Some(4.5)]
+            .into_iter()
+            .map(|limiting_magnitude| {
+                Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.clone(),
+                    object_name: "M31".to_string(),
+                    limiting_magnitude,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let (darkest, brightest) = log_sky_quality_extremes(&client, &user_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(darkest.observation.limiting_magnitude, Some(6.0));
+        assert_eq!(darkest._id, logs[2]._id);
+        assert_eq!(brightest.observation.limiting_magnitude, Some(3.0));
+        assert_eq!(brightest._id, logs[0]._id);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rename_equipment_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_rename_equipment".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = ["Dobson 254/1250", "Refractor 80/400", "Dobson 254/1250"]
+            .into_iter()
+            .map(|equipment| {
+                Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.clone(),
+                    object_name: "M31".to_string(),
+                    equipment: equipment.to_string(),
+                    eyepiece: "25mm".to_string(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let renamed = rename_equipment(&client, &user_id, "Dobson 254/1250", "Dobson 10-inch")
+            .await
+            .unwrap();
+        assert_eq!(renamed, 2);
+        let stored: Vec<Log> = my_coll
+            .find(doc! {"user_id": &user_id})
+            .sort(doc! {"_id": 1})
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let equipment: Vec<&str> = stored
+            .iter()
+            .map(|log| log.observation.equipment.as_str())
+            .collect();
+        assert_eq!(
+            equipment,
+            ["Dobson 10-inch", "Refractor 80/400", "Dobson 10-inch"]
+        );
+        // the new name has no focal length to derive a magnification from
+        assert_eq!(stored[0].observation.magnification, None);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_tag_counts_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_tag_counts".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = [
+            vec!["galaxy", "autumn"],
+            vec!["galaxy"],
+            vec!["nebula", "autumn", "galaxy"],
+            vec![],
+        ]
+        .into_iter()
+        .map(|tags| {
+            Log::from_observation_request(&ObservationRequest {
+                user_id: user_id.clone(),
+                object_name: "M31".to_string(),
+                tags: tags.into_iter().map(str::to_owned).collect(),
+                ..Default::default()
+            })
+        })
+        .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let counts = log_tag_counts(&client, &user_id).await.unwrap();
+        assert_eq!(
+            counts,
+            vec![
+                ("galaxy".to_string(), 3),
+                ("autumn".to_string(), 2),
+                ("nebula".to_string(), 1),
+            ]
+        );
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn detect_coordinate_name_conflicts_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_coordinate_conflicts".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = [
+            ("M31", 10.685, 41.269),
+            ("M32", 10.685, 41.269),
+            // an alias of the first one
+            ("NGC 224", 10.684, 41.27),
+            ("M42", 83.822, -5.391),
+        ]
+        .into_iter()
+        .map(|(object_name, ra, dec)| {
+            Log::from_observation_request(&ObservationRequest {
+                user_id: user_id.clone(),
+                object_name: object_name.to_string(),
+                right_ascension: Some(ra),
+                declination: Some(dec),
+                ..Default::default()
+            })
+        })
+        .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let mut conflicts = detect_coordinate_name_conflicts(&client, &user_id, 0.1)
+            .await
+            .unwrap();
+        conflicts.sort();
+        let ids: Vec<ObjectId> = logs.iter().filter_map(|log| log._id).collect();
+        let mut expected = vec![
+            (ids[0].min(ids[1]), ids[0].max(ids[1])),
+            (ids[1].min(ids[2]), ids[1].max(ids[2])),
+        ];
+        expected.sort();
+        let conflicts: Vec<(ObjectId, ObjectId)> = conflicts
+            .into_iter()
+            .map(|(a, b)| (a.min(b), a.max(b)))
+            .collect();
+        assert_eq!(conflicts, expected);
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn logs_to_ical_test() {
+        let log = |object_name: &str, notes: &str, duration_minutes: Option<i64>| {
+            let mut log = Log::from_observation_request(&ObservationRequest {
+                user_id: USER_ID.into(),
+                object_name: object_name.to_string(),
+                notes: notes.to_string(),
+                duration_minutes,
+                ..Default::default()
+            });
+            log.date = Utc.with_ymd_and_hms(2024, 9, 12, 21, 30, 0).unwrap();
+            log
         };
-        let deleted = log_deletion(&client, &delete_req).await.unwrap();
-        assert_eq!(deleted.deleted_count, 1);
+        let logs = vec![
+            log("M31", "dust lanes, barely\nvisible", Some(45)),
+            log("M42", "", None),
+        ];
+        let calendar = logs_to_ical(&logs);
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(calendar.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(calendar.matches("BEGIN:VEVENT\r\n").count(), 2);
+        assert_eq!(calendar.matches("END:VEVENT\r\n").count(), 2);
+        let summaries: Vec<&str> = calendar
+            .lines()
+            .filter_map(|line| line.strip_prefix("SUMMARY:"))
+            .collect();
+        assert_eq!(summaries, ["M31", "M42"]);
+        assert!(calendar.contains("DTSTART:20240912T213000Z\r\n"));
+        assert!(calendar.contains("DURATION:PT45M\r\n"));
+        assert!(calendar.contains("DESCRIPTION:dust lanes\\, barely\\nvisible\r\n"));
+        assert_eq!(calendar.matches("DESCRIPTION:").count(), 1);
 
-        let saved = log_retrieval(&client, &get_req).await.unwrap();
-        assert!(saved.is_none());
+        // long lines are folded
+        let long = logs_to_ical(&[log("M31", &"a".repeat(100), None)]);
+        assert!(long.lines().all(|line| line.len() <= 75));
+        assert!(long.contains("\r\n a"));
     }
 
     #[tokio::test]
-    async fn log_retrieving_test() {
+    async fn log_frequency_trend_test() {
         let client = mongodb_connection().await.unwrap();
-        let object_name = "M31".to_string();
-        let object_location = "Andromeda".to_string();
-        let equipment = "Dobson 254/1250".to_string();
-        let eyepiece = "25mm".to_string();
-        let notes = "beautiful, even with a bad seeing".to_string();
+        let user_id: UserId = "fake_id_frequency_trend".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        // weeks counted from the epoch start on thursdays, as 2024-01-04
+        let dates = [
+            Utc.with_ymd_and_hms(2024, 1, 4, 21, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 1, 6, 22, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 1, 10, 23, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 1, 18, 20, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2024, 1, 19, 20, 30, 0).unwrap(),
+        ];
+        let logs: Vec<Log> = dates
+            .iter()
+            .map(|date| {
+                let mut log = Log::from_observation_request(&ObservationRequest {
+                    user_id: user_id.clone(),
+                    object_name: "M31".to_string(),
+                    ..Default::default()
+                });
+                log.date = *date;
+                log
+            })
+            .collect();
+        my_coll.insert_many(&logs).await.unwrap();
 
+        let trend = log_frequency_trend(&client, &user_id, 7).await.unwrap();
+        assert_eq!(
+            trend,
+            vec![("2024-01-04".to_string(), 3), ("2024-01-18".to_string(), 2),]
+        );
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn log_undelete_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_undelete".into();
         let req = ObservationRequest {
-            user_id: USER_ID.to_string(),
-            object_name: object_name.clone(),
-            object_location: object_location.clone(),
-            equipment: equipment.clone(),
-            eyepiece: eyepiece.clone(),
-            notes: notes.clone(),
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
         };
         let log = Log::from_observation_request(&req);
-        let res;
-        match log_insertion(&log, &client).await {
-            Ok(option_id) => res = option_id,
-            Err(e) => {
-                println!("res is error: {:?}", e);
-                panic!();
-            }
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+        let delete_req = DeleteLogRequest {
+            user_id: user_id.clone(),
+            log_id,
         };
-        let res = res.unwrap();
-        let get_req = GetLogRequest {
-            user_id: USER_ID.to_string(),
-            log_id: res.clone(),
+        log_soft_delete(&client, &delete_req).await.unwrap();
+
+        let mut get_req = GetLogRequest {
+            user_id: user_id.clone(),
+            log_id,
+            if_modified_since: None,
+            format: LogFormat::Json,
+            pretty: false,
+            include_deleted: false,
         };
-        let saved = log_retrieval(&client, &get_req).await.unwrap().unwrap();
+        assert!(log_retrieval(&client, &get_req).await.unwrap().is_none());
+        get_req.include_deleted = true;
+        let deleted = log_retrieval(&client, &get_req).await.unwrap().unwrap();
+        assert!(deleted.deleted_at.is_some());
 
-        assert_eq!(saved.user_id, USER_ID.to_string());
-        assert_eq!(saved.observation.object_name, object_name);
-        assert_eq!(saved.observation.object_location, object_location);
-        assert_eq!(saved.observation.equipment, equipment);
-        assert_eq!(saved.observation.eyepiece, eyepiece);
-        assert_eq!(saved.observation.notes, notes);
+        // someone else can't bring it back
+        let res = log_undelete(&client, log_id, &"fake_id_other".into())
+            .await
+            .unwrap();
+        assert_eq!(res.modified_count, 0);
+        let res = log_undelete(&client, log_id, &user_id).await.unwrap();
+        assert_eq!(res.modified_count, 1);
 
-        let delete_req = DeleteLogRequest {
-            user_id: USER_ID.to_string(),
-            log_id: res,
+        let list_req = GetListRequest {
+            user_id: user_id.clone(),
+            ..Default::default()
         };
-        let deleted = log_deletion(&client, &delete_req).await.unwrap();
-        assert_eq!(deleted.deleted_count, 1);
+        let listed: Vec<Log> = log_listing(&client, &list_req)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]._id, Some(log_id));
+        assert!(listed[0].deleted_at.is_none());
+
+        //delete
+        log_deletion(&client, &delete_req).await.unwrap();
     }
 
     #[tokio::test]
-    async fn log_replacing_test() {
+    async fn scan_invalid_documents_test() {
         let client = mongodb_connection().await.unwrap();
-        let req_1 = ObservationRequest {
-            user_id: USER_ID.to_string(),
+        let user_id: UserId = "fake_id_scan_invalid".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Document>(&CONFIG.database_collection);
+        let log = Log::from_observation_request(&ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        });
+        log_insertion(&log, &client).await.unwrap();
+        let malformed_id = ObjectId::new();
+        my_coll
+            .insert_one(doc! {
+                "_id": malformed_id,
+                "user_id": &user_id,
+                "date": "yesterday evening",
+                "observation": {"object_name": "M42"},
+            })
+            .await
+            .unwrap();
+
+        let invalid = scan_invalid_documents(&client).await.unwrap();
+        assert!(invalid.contains(&malformed_id));
+        assert!(!invalid.contains(&log._id.unwrap()));
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn diff_observations_test() {
+        let req = ObservationRequest {
+            user_id: USER_ID.into(),
             object_name: "M31".to_string(),
-            object_location: "Andromeda".to_string(),
-            equipment: "Dobson 254/1250".to_string(),
             eyepiece: "25mm".to_string(),
-            notes: "beautiful, even with a bad seeing".to_string(),
+            notes: "core only".to_string(),
+            ..Default::default()
         };
-        let log = Log::from_observation_request(&req_1);
-        let res;
-        match log_insertion(&log, &client).await {
-            Ok(option_id) => res = option_id,
-            Err(e) => {
-                println!("res is error: {:?}", e);
-                panic!();
-            }
+        let first = Observation::from_request(&req);
+        let mut second = first.clone();
+        second.eyepiece = "10mm".to_string();
+        second.notes = "dust lane visible".to_string();
+        assert_eq!(
+            diff_observations(&first, &second),
+            vec![
+                FieldDiff {
+                    field: "eyepiece".to_string(),
+                    old: "25mm".into(),
+                    new: "10mm".into(),
+                },
+                FieldDiff {
+                    field: "notes".to_string(),
+                    old: "core only".into(),
+                    new: "dust lane visible".into(),
+                },
+            ]
+        );
+        assert!(diff_observations(&first, &first).is_empty());
+    }
+
+    #[tokio::test]
+    async fn log_diff_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_diff";
+        let mut ids = Vec::new();
+        for seeing in [Some(2), Some(4)] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: "M31".to_string(),
+                seeing,
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let diff_req = LogPairRequest {
+            user_id: user_id.into(),
+            first: ids[0],
+            second: ids[1],
         };
-        let res = res.unwrap();
-        let get_req = GetLogRequest {
-            user_id: USER_ID.to_string(),
-            log_id: res.clone(),
+        let diff = log_diff(&client, &diff_req).await.unwrap();
+        let fields: Vec<&str> = diff.iter().map(|diff| diff.field.as_str()).collect();
+        assert!(fields.contains(&"seeing"));
+        let foreign = LogPairRequest {
+            user_id: "fake_id_other".into(),
+            first: ids[0],
+            second: ids[1],
         };
-        let saved = log_retrieval(&client, &get_req).await.unwrap();
-        assert!(saved.is_some());
-
-        //replace
-        let req_2 = ObservationRequest {
-            user_id: USER_ID.to_string(),
-            object_name: "M1".to_string(),
-            object_location: "Taurus".to_string(),
-            equipment: "Dobson 254/1200".to_string(),
-            eyepiece: "10mm".to_string(),
-            notes: "crab nebula".to_string(),
+        assert!(matches!(
+            log_diff(&client, &foreign).await,
+            Err(NightlogError::NotFound)
+        ));
+        let same = LogPairRequest {
+            user_id: user_id.into(),
+            first: ids[0],
+            second: ids[0],
         };
-        let mut log_2 = Log::from_observation_request(&req_2);
-        log_2._id = Some(res.clone());
-        let rep = log_replacement(&log_2, &client).await.unwrap();
-        assert_eq!(rep.modified_count, 1);
+        assert!(log_diff(&client, &same).await.unwrap().is_empty());
 
-        let replaced = log_retrieval(&client, &get_req).await.unwrap().unwrap();
-        assert_eq!(replaced.user_id, log_2.user_id);
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn log_setup_stats_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_setup_stats".into();
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Log>(&CONFIG.database_collection);
+        let logs: Vec<Log> = [
+            ("Dobson 254/1250", "25mm"),
+            ("Dobson 254/1250", "10mm"),
+            ("Dobson 254/1250", "25mm"),
+        ]
+        .into_iter()
+        .map(|(equipment, eyepiece)| {
+            Log::from_observation_request(&ObservationRequest {
+                user_id: user_id.clone(),
+                object_name: "M31".to_string(),
+                equipment: equipment.to_string(),
+                eyepiece: eyepiece.to_string(),
+                ..Default::default()
+            })
+        })
+        .collect();
+        my_coll.insert_many(&logs).await.unwrap();
+
+        let setup = |equipment: &str, eyepiece: &str| (equipment.to_string(), eyepiece.to_string());
         assert_eq!(
-            replaced.observation.object_name,
-            log_2.observation.object_name
+            log_setup_stats(&client, &user_id).await.unwrap(),
+            vec![
+                (setup("Dobson 254/1250", "25mm"), 2),
+                (setup("Dobson 254/1250", "10mm"), 1),
+            ]
         );
+
+        //delete
+        my_coll
+            .delete_many(doc! {"user_id": &user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn backfill_moon_phase_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_backfill_moon";
+        let my_coll = client
+            .database(&CONFIG.database_name)
+            .collection::<Document>(&CONFIG.database_collection);
+        let date = Utc.with_ymd_and_hms(2024, 4, 8, 18, 0, 0).unwrap();
+        let log_id = ObjectId::new();
+        my_coll
+            .insert_one(doc! {
+                "_id": log_id,
+                "user_id": user_id,
+                "date": date_bson(&date),
+                "observation": {
+                    "object_name": "M31",
+                    "object_location": "",
+                    "equipment": "",
+                    "eyepiece": "",
+                    "notes": "",
+                },
+            })
+            .await
+            .unwrap();
+
         assert_eq!(
-            replaced.observation.object_location,
-            log_2.observation.object_location
+            backfill_moon_phase(&client, Some(&user_id.into()))
+                .await
+                .unwrap(),
+            1
         );
-        assert_eq!(replaced.observation.equipment, log_2.observation.equipment);
-        assert_eq!(replaced.observation.eyepiece, log_2.observation.eyepiece);
-        assert_eq!(replaced.observation.notes, log_2.observation.notes);
+        let stored = my_coll
+            .find_one(doc! {"_id": log_id})
+            .await
+            .unwrap()
+            .unwrap();
+        let illumination = stored
+            .get_document("observation")
+            .unwrap()
+            .get_f64("moon_illumination")
+            .unwrap();
+        assert_eq!(illumination, moon_illumination(&date));
+        // nothing left to fill
+        assert_eq!(
+            backfill_moon_phase(&client, Some(&user_id.into()))
+                .await
+                .unwrap(),
+            0
+        );
+
         //delete
-        let delete_req = DeleteLogRequest {
-            user_id: USER_ID.to_string(),
-            log_id: res,
+        my_coll
+            .delete_many(doc! {"user_id": user_id})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn with_timeout_test() {
+        let limit = std::time::Duration::from_millis(20);
+        let slow = async {
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            Ok::<_, mongodb::error::Error>(1)
         };
-        let deleted = log_deletion(&client, &delete_req).await.unwrap();
-        assert_eq!(deleted.deleted_count, 1);
+        let error = with_timeout(limit, slow).await.unwrap_err();
+        assert!(matches!(error, NightlogError::Timeout(elapsed) if elapsed == limit));
+        assert_eq!(error.status_code(), 504);
+
+        let fast = async { Ok::<_, mongodb::error::Error>(1) };
+        assert_eq!(with_timeout(limit, fast).await.unwrap(), 1);
+        let failing = async { Err::<u8, _>(mongodb::error::Error::custom("failover")) };
+        assert!(matches!(
+            with_timeout(limit, failing).await,
+            Err(NightlogError::Database(_))
+        ));
     }
 
     #[tokio::test]
-    async fn log_listing_test() {
+    async fn object_name_sort_test() {
         let client = mongodb_connection().await.unwrap();
-        let req_1 = ObservationRequest {
-            user_id: USER_ID.to_string(),
-            object_name: "M31".to_string(),
-            object_location: "Andromeda".to_string(),
-            equipment: "Dobson 254/1250".to_string(),
-            eyepiece: "25mm".to_string(),
-            notes: "beautiful, even with a bad seeing".to_string(),
+        let user_id = "fake_id_name_sort";
+        let mut ids = Vec::new();
+        for object_name in ["M31", "m1", "NGC224"] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let list_req = GetListRequest {
+            user_id: user_id.into(),
+            sort: SortOrder::ObjectNameAsc,
+            ..Default::default()
         };
-        let log = Log::from_observation_request(&req_1);
-        match log_insertion(&log, &client).await {
-            Err(e) => {
-                println!("res is error: {:?}", e);
-                panic!();
-            }
-            _ => {}
+        let logs: Vec<Log> = log_listing(&client, &list_req)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let names: Vec<&str> = logs
+            .iter()
+            .map(|log| log.observation.object_name.as_str())
+            .collect();
+        assert_eq!(names, ["m1", "M31", "NGC224"]);
+        let listed = log_listing_ids(&client, &list_req).await.unwrap();
+        assert_eq!(listed, vec![ids[1], ids[0], ids[2]]);
+
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn has_observed_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_has_observed".into();
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
         };
+        let log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
 
-        let req_2 = ObservationRequest {
-            user_id: USER_ID.to_string(),
-            object_name: "M1".to_string(),
-            object_location: "Taurus".to_string(),
-            equipment: "Dobson 254/1200".to_string(),
-            eyepiece: "10mm".to_string(),
-            notes: "crab nebula".to_string(),
+        assert!(has_observed(&client, &user_id, "M31").await.unwrap());
+        assert!(!has_observed(&client, &user_id, "M42").await.unwrap());
+
+        //delete
+        let delete_req = DeleteLogRequest { user_id, log_id };
+        log_deletion(&client, &delete_req).await.unwrap();
+    }
+
+    #[test]
+    fn angular_separation_test() {
+        // Mizar and Alcor, 11.8 arcminutes apart
+        let separation = angular_separation(200.98125, 54.92528, 201.30625, 54.98806);
+        assert!((separation * 60.0 - 11.81).abs() < 0.05);
+        assert!((angular_separation(0.0, 90.0, 123.0, 0.0) - 90.0).abs() < 1e-9);
+        assert!((angular_separation(10.0, 20.0, 190.0, -20.0) - 180.0).abs() < 1e-9);
+        assert_eq!(angular_separation(83.633, 22.0145, 83.633, 22.0145), 0.0);
+    }
+
+    #[tokio::test]
+    async fn log_separation_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_separation";
+        let mut ids = Vec::new();
+        for (object_name, right_ascension, declination) in [
+            ("Mizar", Some(200.98125), Some(54.92528)),
+            ("Alcor", Some(201.30625), Some(54.98806)),
+            ("M42", None, None),
+        ] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: object_name.to_string(),
+                right_ascension,
+                declination,
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        let separation_req = LogPairRequest {
+            user_id: user_id.into(),
+            first: ids[0],
+            second: ids[1],
         };
-        let log_2 = Log::from_observation_request(&req_2);
-        match log_insertion(&log_2, &client).await {
-            Err(e) => {
-                println!("res is error: {:?}", e);
-                panic!();
-            }
-            _ => {}
+        let separation = log_separation(&client, &separation_req).await.unwrap();
+        assert!((separation * 60.0 - 11.81).abs() < 0.05);
+        let without_coordinates = LogPairRequest {
+            user_id: user_id.into(),
+            first: ids[0],
+            second: ids[2],
+        };
+        assert!(matches!(
+            log_separation(&client, &without_coordinates).await,
+            Err(NightlogError::Invalid(_))
+        ));
+        let foreign = LogPairRequest {
+            user_id: "fake_id_other".into(),
+            first: ids[0],
+            second: ids[1],
         };
+        assert!(matches!(
+            log_separation(&client, &foreign).await,
+            Err(NightlogError::NotFound)
+        ));
+        let same = LogPairRequest {
+            user_id: user_id.into(),
+            first: ids[0],
+            second: ids[0],
+        };
+        assert_eq!(log_separation(&client, &same).await.unwrap(), 0.0);
 
-        //test
-        let list_req = GetListRequest {
-            user_id: "fake_id".to_string(),
+        //delete
+        for log_id in ids {
+            let delete_req = DeleteLogRequest {
+                user_id: user_id.into(),
+                log_id,
+            };
+            log_deletion(&client, &delete_req).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn share_card_test() {
+        let req = ObservationRequest {
+            user_id: "fake_id".into(),
+            object_name: "M31".to_string(),
+            notes: format!("dust lane\nvisible {}", "x".repeat(200)),
+            ..Default::default()
         };
-        let cursor = log_listing(&client, &list_req).await.unwrap();
-        let list = match cursor.try_collect::<Vec<Log>>().await {
-            Ok(vector) => vector,
-            Err(e) => {
-                panic!(
-                    "an error occurred in collecting user's logs in a vector: {}",
-                    e
-                );
-            }
+        let mut log = Log::from_observation_request(&req);
+        log.date = Utc.with_ymd_and_hms(2024, 3, 10, 21, 30, 0).unwrap();
+        let card = log.to_share_card();
+        assert_eq!(card.object_name, "M31");
+        assert_eq!(card.date, "2024-03-10");
+        assert!(card.summary.starts_with("dust lane visible x"));
+        assert_eq!(card.summary.chars().count(), SHARE_PREVIEW_LENGTH + 1);
+        assert!(card.summary.ends_with('…'));
+
+        log.observation.notes = "core only".to_string();
+        assert_eq!(log.to_share_card().summary, "core only");
+    }
+
+    #[tokio::test]
+    async fn soft_deleted_share_card_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id: UserId = "fake_id_share_card".into();
+        let req = ObservationRequest {
+            user_id: user_id.clone(),
+            object_name: "M31".to_string(),
+            ..Default::default()
         };
-        assert_eq!(list.len(), 2);
+        let log = Log::from_observation_request(&req);
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+        let share_req = ShareCardRequest {
+            token: log_create_share(&client, log_id, &user_id).await.unwrap(),
+        };
+        let card = log_share_card(&client, &share_req).await.unwrap();
+        assert_eq!(card.object_name, "M31");
+
+        let del_req = DeleteLogRequest { user_id, log_id };
+        log_soft_delete(&client, &del_req).await.unwrap();
+        assert!(matches!(
+            log_share_card(&client, &share_req).await,
+            Err(NightlogError::NotFound)
+        ));
 
         //delete
-        for log in list {
+        log_deletion(&client, &del_req).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn has_attachments_filter_test() {
+        let client = mongodb_connection().await.unwrap();
+        let user_id = "fake_id_attachments";
+        let mut ids = Vec::new();
+        for attachments in [vec!["sketches/m31.png".to_string()], Vec::new()] {
+            let req = ObservationRequest {
+                user_id: user_id.into(),
+                object_name: "M31".to_string(),
+                attachments,
+                ..Default::default()
+            };
+            let log = Log::from_observation_request(&req);
+            ids.push(log_insertion(&log, &client).await.unwrap().unwrap());
+        }
+
+        for (has_attachments, expected) in [(true, ids[0]), (false, ids[1])] {
+            let list_req = GetListRequest {
+                user_id: user_id.into(),
+                has_attachments: Some(has_attachments),
+                ..Default::default()
+            };
+            let listed = log_listing_ids(&client, &list_req).await.unwrap();
+            assert_eq!(listed, vec![expected]);
+        }
+
+        //delete
+        for log_id in ids {
             let delete_req = DeleteLogRequest {
-                user_id: USER_ID.to_string(),
-                log_id: log._id.unwrap(),
+                user_id: user_id.into(),
+                log_id,
             };
-            let deleted = log_deletion(&client, &delete_req).await.unwrap();
-            assert_eq!(deleted.deleted_count, 1);
+            log_deletion(&client, &delete_req).await.unwrap();
         }
     }
+
+    #[test]
+    fn compute_altitude_test() {
+        let (site_lat, site_lon) = (45.0, 9.19);
+        let at = Utc.with_ymd_and_hms(2024, 1, 15, 21, 0, 0).unwrap();
+        // on the meridian an object is at its highest, 90 - |latitude - declination|
+        let ra = local_sidereal_time(at, site_lon);
+        assert!((compute_altitude(ra, 20.0, site_lat, site_lon, at) - 65.0).abs() < 1e-6);
+        assert!((compute_altitude(ra, 45.0, site_lat, site_lon, at) - 90.0).abs() < 1e-6);
+        let earlier = compute_altitude(ra, 20.0, site_lat, site_lon, at - Duration::hours(2));
+        let later = compute_altitude(ra, 20.0, site_lat, site_lon, at + Duration::hours(2));
+        assert!(earlier < 65.0 && later < 65.0);
+        assert!((earlier - later).abs() < 0.1);
+    }
+
+    #[test]
+    fn fill_altitude_test() {
+        let req = ObservationRequest {
+            user_id: "fake_id".into(),
+            object_name: "M31".to_string(),
+            right_ascension: Some(10.6847),
+            declination: Some(41.2690),
+            site_latitude: Some(45.0),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        assert_eq!(log.observation.altitude_deg, None);
+
+        let mut log = Log::from_observation_request(&ObservationRequest {
+            site_longitude: Some(9.19),
+            ..req
+        });
+        assert!(log.observation.altitude_deg.is_some());
+        // the computed altitude follows the date
+        let date = Utc.with_ymd_and_hms(2024, 1, 15, 21, 0, 0).unwrap();
+        log.set_date(date);
+        assert_eq!(
+            log.observation.altitude_deg,
+            Some(compute_altitude(10.6847, 41.2690, 45.0, 9.19, date))
+        );
+
+        // what the observer gave is kept
+        let mut log = Log::from_observation_request(&ObservationRequest {
+            user_id: "fake_id".into(),
+            object_name: "M31".to_string(),
+            right_ascension: Some(10.6847),
+            declination: Some(41.2690),
+            site_latitude: Some(45.0),
+            site_longitude: Some(9.19),
+            altitude_deg: Some(30.0),
+            ..Default::default()
+        });
+        log.set_date(date + Duration::hours(2));
+        assert_eq!(log.observation.altitude_deg, Some(30.0));
+        assert!(!log.observation.altitude_computed);
+    }
 }
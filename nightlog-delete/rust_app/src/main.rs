@@ -1,33 +1,34 @@
+#![allow(clippy::empty_line_after_doc_comments)]
+
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use nightlog_common::{log_deletion, mongodb_connection, DeleteLogRequest};
-use serde::Serialize;
+use nightlog_common::{
+    connect_with_retry, log_delete_returning, log_soft_delete_returning, with_retry, ApiResponse,
+    DeleteLogRequest, Log, CONFIG,
+};
 /// Requests come into the runtime as unicode
 /// strings in json format, which can map to any structure that implements `serde::Deserialize`
 /// The runtime pays no attention to the contents of the request payload.
 
-/// This is a made-up example of what a response structure may look like.
-/// There is no restriction on what it can be. The runtime requires responses
-/// to be serialized into json. The runtime pays no attention
-/// to the contents of the response payload.
-#[allow(non_snake_case)]
-#[derive(Serialize)]
-struct Response {
-    statusCode: i32,
-    body: u64,
-}
-
 /// This is the main body for the function.
-async fn function_handler(event: LambdaEvent<DeleteLogRequest>) -> Result<Response, Error> {
-    let mongodb_client = mongodb_connection().await?;
+async fn function_handler(event: LambdaEvent<DeleteLogRequest>) -> Result<ApiResponse, Error> {
+    let mongodb_client = connect_with_retry().await?;
     let log_req = event.payload;
-    let res = log_deletion(&mongodb_client, &log_req).await?;
-    // Prepare the response
-    let resp = Response {
-        statusCode: 200,
-        body: res.deleted_count,
+    let res = if CONFIG.features.soft_delete {
+        with_retry(|| log_soft_delete_returning(&mongodb_client, &log_req)).await?
+    } else {
+        with_retry(|| log_delete_returning(&mongodb_client, &log_req)).await?
     };
 
-    // Return `Response` (it will be serialized to JSON automatically by the runtime)
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
+    build_response(res.as_ref())
+}
+
+/// Prepares the response with the deleted log, so that the client can tell what was removed.
+fn build_response(deleted: Option<&Log>) -> Result<ApiResponse, Error> {
+    let resp = match deleted {
+        Some(log) => ApiResponse::new(200, serde_json::to_string(&log.to_public())?),
+        None => ApiResponse::new(404, String::new()),
+    };
     Ok(resp)
 }
 
@@ -45,4 +46,28 @@ async fn main() -> Result<(), Error> {
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use crate::build_response;
+    use nightlog_common::{Log, ObservationRequest};
+
+    #[test]
+    fn deleted_log_test() {
+        let req = ObservationRequest {
+            user_id: "fake_id".into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let log = Log::from_observation_request(&req);
+        let resp = build_response(Some(&log)).unwrap();
+        assert_eq!(resp.statusCode, 200);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body["observation"]["objectName"], "M31");
+    }
+
+    #[test]
+    fn missing_log_test() {
+        let resp = build_response(None).unwrap();
+        assert_eq!(resp.statusCode, 404);
+        assert!(resp.body.is_empty());
+    }
+}
@@ -0,0 +1,70 @@
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nightlog_common::{
+    connect_with_retry, log_diff, with_retry, ApiResponse, FieldDiff, LogPairRequest, NightlogError,
+};
+
+// Requests come into the runtime as unicode
+// strings in json format, which can map to any structure that implements `serde::Deserialize`
+// The runtime pays no attention to the contents of the request payload.
+
+/// The diff is the body, a missing log gets its status with the error as body.
+fn build_response(res: Result<Vec<FieldDiff>, NightlogError>) -> Result<ApiResponse, Error> {
+    match res {
+        Ok(diff) => Ok(ApiResponse::new(200, serde_json::to_string(&diff)?)),
+        Err(e @ NightlogError::NotFound) => Ok(e.to_response()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// This is the main body for the function.
+async fn function_handler(event: LambdaEvent<LogPairRequest>) -> Result<ApiResponse, Error> {
+    let mongodb_client = connect_with_retry().await?;
+    let diff_req = event.payload;
+    let res = with_retry(|| log_diff(&mongodb_client, &diff_req)).await;
+
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
+    build_response(res)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_response;
+    use nightlog_common::{FieldDiff, NightlogError};
+
+    #[test]
+    fn diff_response_test() {
+        let diff = vec![FieldDiff {
+            field: "eyepiece".to_string(),
+            old: "25mm".into(),
+            new: "10mm".into(),
+        }];
+        let resp = build_response(Ok(diff)).unwrap();
+        assert_eq!(resp.statusCode, 200);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!([{"field": "eyepiece", "old": "25mm", "new": "10mm"}])
+        );
+    }
+
+    #[test]
+    fn not_found_response_test() {
+        let resp = build_response(Err(NightlogError::NotFound)).unwrap();
+        assert_eq!(resp.statusCode, 404);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body["error"], "not_found");
+    }
+}
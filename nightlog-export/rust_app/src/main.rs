@@ -0,0 +1,72 @@
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nightlog_common::{
+    connect_with_retry, log_export_csv, with_retry, ApiResponse, CsvChunk, ExportRequest,
+};
+
+// Requests come into the runtime as unicode
+// strings in json format, which can map to any structure that implements `serde::Deserialize`
+// The runtime pays no attention to the contents of the request payload.
+
+/// The body is the csv itself, the `next` token travels in a header and is missing
+/// on the last chunk.
+fn build_response(chunk: CsvChunk) -> ApiResponse {
+    let resp = ApiResponse::new(200, chunk.csv).with_header("Content-Type", "text/csv");
+    match chunk.next {
+        Some(next) => resp.with_header("X-Next-Token", &next.to_string()),
+        None => resp,
+    }
+}
+
+/// This is the main body for the function.
+async fn function_handler(event: LambdaEvent<ExportRequest>) -> Result<ApiResponse, Error> {
+    let mongodb_client = connect_with_retry().await?;
+    let export_req = event.payload;
+    let chunk = match with_retry(|| log_export_csv(&mongodb_client, &export_req)).await {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            return Err(format!("an error occurred in exporting user's logs: {}", e).into());
+        }
+    };
+
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
+    Ok(build_response(chunk))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_response;
+    use nightlog_common::CsvChunk;
+
+    #[test]
+    fn next_token_header_test() {
+        let chunk = CsvChunk {
+            csv: "id,date\n".to_string(),
+            next: Some(1000),
+        };
+        let resp = build_response(chunk);
+        assert_eq!(resp.headers["X-Next-Token"], "1000");
+        assert_eq!(resp.headers["Content-Type"], "text/csv");
+    }
+
+    #[test]
+    fn last_chunk_without_token_test() {
+        let chunk = CsvChunk {
+            csv: String::new(),
+            next: None,
+        };
+        assert!(!build_response(chunk).headers.contains_key("X-Next-Token"));
+    }
+}
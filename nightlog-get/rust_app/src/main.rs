@@ -1,37 +1,65 @@
+#![allow(clippy::empty_line_after_doc_comments, clippy::useless_format)]
+
+use chrono::{DateTime, Utc};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use nightlog_common::{log_retrieval, mongodb_connection, GetLogRequest};
-use serde::Serialize;
+use mongodb::Client;
+use nightlog_common::{
+    log_retrieval, mongodb_connection, render_notes_html, to_api_json, with_retry, ApiResponse,
+    GetLogRequest, JsonStyle, Log, LogFormat,
+};
+use std::future::IntoFuture;
 
 /// Requests come into the runtime as unicode
 /// strings in json format, which can map to any structure that implements `serde::Deserialize`
 /// The runtime pays no attention to the contents of the request payload.
 
-/// There is no restriction on what it can be. The runtime requires responses
-/// to be serialized into json. The runtime pays no attention
-/// to the contents of the response payload.
-#[allow(non_snake_case)]
-#[derive(Serialize)]
-struct Response {
-    statusCode: i32,
-    body: String,
+/// This is the main body for the function.
+async fn function_handler(event: LambdaEvent<GetLogRequest>) -> Result<ApiResponse, Error> {
+    respond(event, mongodb_connection).await
 }
 
-/// This is the main body for the function.
-async fn function_handler(event: LambdaEvent<GetLogRequest>) -> Result<Response, Error> {
-    let mongodb_client = mongodb_connection().await?;
+/// The connector is given, so that the retry of a failed connection can be tested.
+/// The handler runs it like `connect_with_retry` does.
+async fn respond<F, Fut>(
+    event: LambdaEvent<GetLogRequest>,
+    connect: F,
+) -> Result<ApiResponse, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: IntoFuture<Output = Result<Client, mongodb::error::Error>>,
+{
+    let mongodb_client = with_retry(connect).await?;
     let log_req = event.payload;
-    let res = log_retrieval(&mongodb_client, &log_req).await?;
+    let res = with_retry(|| log_retrieval(&mongodb_client, &log_req)).await?;
     let Some(log) = res else {
         return Err(format!("no id returned from insert operation").into());
     };
-    let body = serde_json::to_string(&log)?;
-    // Prepare the response
-    let resp = Response {
-        statusCode: 200,
-        body,
+
+    let style = JsonStyle {
+        pretty: log_req.pretty,
     };
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
+    build_response(&log, log_req.if_modified_since, log_req.format, style)
+}
 
-    // Return `Response` (it will be serialized to JSON automatically by the runtime)
+/// Prepares the response, leaving the body empty with a 304 when the client
+/// copy of the log is still current.
+fn build_response(
+    log: &Log,
+    if_modified_since: Option<DateTime<Utc>>,
+    format: LogFormat,
+    style: JsonStyle,
+) -> Result<ApiResponse, Error> {
+    if let Some(since) = if_modified_since {
+        if !log.modified_since(&since) {
+            return Ok(ApiResponse::new(304, String::new()));
+        }
+    }
+    let resp = match format {
+        LogFormat::Json => ApiResponse::new(200, to_api_json(&log.to_public(), style)?),
+        LogFormat::Html => ApiResponse::new(200, render_notes_html(&log.observation.notes))
+            .with_header("Content-Type", "text/html; charset=utf-8"),
+    };
     Ok(resp)
 }
 
@@ -49,4 +77,124 @@ async fn main() -> Result<(), Error> {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use crate::{build_response, respond};
+    use chrono::Duration;
+    use lambda_runtime::{Context, LambdaEvent};
+    use mongodb::Client;
+    use nightlog_common::{
+        log_deletion, log_insertion, mongodb_connection, JsonStyle, Log, LogFormat,
+        ObservationRequest,
+    };
+
+    fn saved_log() -> Log {
+        let req = ObservationRequest {
+            user_id: "fake_id".into(),
+            object_name: "M31".to_string(),
+            notes: "**beautiful**, even with a bad seeing".to_string(),
+            ..Default::default()
+        };
+        Log::from_observation_request(&req)
+    }
+
+    #[test]
+    fn unchanged_log_test() {
+        let log = saved_log();
+        let since = log.updated_at.unwrap() + Duration::minutes(5);
+        let resp =
+            build_response(&log, Some(since), LogFormat::Json, JsonStyle::default()).unwrap();
+        assert_eq!(resp.statusCode, 304);
+        assert!(resp.body.is_empty());
+    }
+
+    #[test]
+    fn changed_log_test() {
+        let log = saved_log();
+        let since = log.updated_at.unwrap() - Duration::minutes(5);
+        let resp =
+            build_response(&log, Some(since), LogFormat::Json, JsonStyle::default()).unwrap();
+        assert_eq!(resp.statusCode, 200);
+        assert_eq!(resp.body, serde_json::to_string(&log.to_public()).unwrap());
+
+        let resp = build_response(&log, None, LogFormat::Json, JsonStyle::default()).unwrap();
+        assert_eq!(resp.statusCode, 200);
+    }
+
+    #[test]
+    fn html_notes_test() {
+        let log = saved_log();
+        let resp = build_response(&log, None, LogFormat::Html, JsonStyle::default()).unwrap();
+        assert_eq!(resp.statusCode, 200);
+        assert_eq!(
+            resp.headers.get("Content-Type").map(String::as_str),
+            Some("text/html; charset=utf-8")
+        );
+        assert!(resp.body.contains("<strong>beautiful</strong>"));
+    }
+
+    #[test]
+    fn internal_fields_hidden_test() {
+        let mut log = saved_log();
+        log.share_token = Some("token".to_string());
+        let resp = build_response(&log, None, LogFormat::Json, JsonStyle::default()).unwrap();
+        assert!(!resp.body.contains("share_token"));
+        assert!(!resp.body.contains("deleted_at"));
+    }
+
+    #[test]
+    fn camel_case_body_test() {
+        let log = saved_log();
+        let resp = build_response(&log, None, LogFormat::Json, JsonStyle::default()).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body["observation"]["objectName"], "M31");
+        assert_eq!(body["userId"], "fake_id");
+    }
+
+    #[test]
+    fn pretty_body_test() {
+        let log = saved_log();
+        let resp = build_response(&log, None, LogFormat::Json, JsonStyle::default()).unwrap();
+        assert!(!resp.body.contains('\n'));
+
+        let style = JsonStyle { pretty: true };
+        let resp = build_response(&log, None, LogFormat::Json, style).unwrap();
+        assert!(resp.body.contains("\n  \"userId\": \"fake_id\""));
+    }
+
+    #[tokio::test]
+    async fn connection_retry_test() {
+        let client = mongodb_connection().await.unwrap();
+        let log = saved_log();
+        let log_id = log_insertion(&log, &client).await.unwrap().unwrap();
+        let ids = serde_json::json!({"logId": {"$oid": log_id.to_hex()}, "userId": "fake_id"});
+
+        // nothing listens there, so the first connection finds no server
+        let unreachable =
+            Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+                .await
+                .unwrap();
+        let attempts = std::cell::Cell::new(0);
+        let connect = || {
+            attempts.set(attempts.get() + 1);
+            let failing = attempts.get() == 1;
+            let unreachable = &unreachable;
+            async move {
+                if failing {
+                    unreachable.list_database_names().await?;
+                }
+                mongodb_connection().await
+            }
+        };
+        let event = LambdaEvent::new(
+            serde_json::from_value(ids.clone()).unwrap(),
+            Context::default(),
+        );
+        let resp = respond(event, connect).await.unwrap();
+        assert_eq!(resp.statusCode, 200);
+        assert_eq!(attempts.get(), 2);
+
+        //delete
+        let delete_req = serde_json::from_value(ids).unwrap();
+        log_deletion(&client, &delete_req).await.unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nightlog_common::{
+    connect_with_retry, log_import, with_retry, ApiResponse, FieldError, ImportRequest, CONFIG,
+};
+
+// Requests come into the runtime as unicode
+// strings in json format, which can map to any structure that implements `serde::Deserialize`
+// The runtime pays no attention to the contents of the request payload.
+
+/// Oversized imports are answered with a 400 before anything is saved.
+fn check_import_size(import_req: &ImportRequest, max_import: usize) -> Result<(), ApiResponse> {
+    if import_req.logs.len() <= max_import {
+        return Ok(());
+    }
+    let message = format!("must not hold more than {} entries", max_import);
+    let body = serde_json::json!([FieldError::new("logs", &message)]);
+    Err(ApiResponse::new(400, body.to_string()))
+}
+
+/// This is the main body for the function.
+async fn function_handler(event: LambdaEvent<ImportRequest>) -> Result<ApiResponse, Error> {
+    let import_req = event.payload;
+    if let Err(resp) = check_import_size(&import_req, CONFIG.max_import) {
+        return Ok(resp);
+    }
+    let mongodb_client = connect_with_retry().await?;
+    let body = match with_retry(|| log_import(&mongodb_client, &import_req)).await {
+        Ok(outcomes) => serde_json::to_string(&outcomes)?,
+        Err(e) => {
+            return Err(format!("an error occurred in importing user's logs: {}", e).into());
+        }
+    };
+    // Prepare the response
+    let resp = ApiResponse::new(200, body);
+
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
+    Ok(resp)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::check_import_size;
+    use nightlog_common::{FieldError, ImportOutcome, ImportRequest};
+
+    #[test]
+    fn import_request_without_entry_user_test() {
+        let req: ImportRequest = serde_json::from_value(serde_json::json!({
+            "userId": "fake_id",
+            "logs": [{
+                "objectName": "M31",
+                "objectLocation": "Andromeda",
+                "equipment": "Dobson 254/1250",
+                "eyepiece": "25mm",
+                "notes": "imported"
+            }]
+        }))
+        .unwrap();
+        assert_eq!(req.logs.len(), 1);
+        assert_eq!(req.logs[0].object_name, "M31");
+    }
+
+    #[test]
+    fn invalid_outcome_body_test() {
+        let errors = vec![FieldError::new("objectName", "must not be empty")];
+        let outcome = ImportOutcome::Invalid(errors);
+        assert_eq!(
+            serde_json::to_value(outcome).unwrap(),
+            serde_json::json!({"invalid": [{"field": "objectName", "message": "must not be empty"}]})
+        );
+    }
+
+    #[test]
+    fn oversized_import_test() {
+        let entry = serde_json::json!({
+            "objectName": "M31",
+            "objectLocation": "Andromeda",
+            "equipment": "Dobson 254/1250",
+            "eyepiece": "25mm",
+            "notes": ""
+        });
+        let req: ImportRequest = serde_json::from_value(serde_json::json!({
+            "userId": "fake_id",
+            "logs": [entry.clone(), entry.clone(), entry]
+        }))
+        .unwrap();
+        assert!(check_import_size(&req, 3).is_ok());
+
+        let resp = check_import_size(&req, 2).unwrap_err();
+        assert_eq!(resp.statusCode, 400);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body[0]["field"], "logs");
+        assert_eq!(body[0]["message"], "must not hold more than 2 entries");
+    }
+}
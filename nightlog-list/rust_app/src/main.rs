@@ -1,31 +1,104 @@
-use futures::TryStreamExt;
+#![allow(clippy::empty_line_after_doc_comments)]
+
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use nightlog_common::{log_listing, mongodb_connection, GetListRequest, Log};
+use mongodb::bson::oid::ObjectId;
+use nightlog_common::{
+    connect_with_retry, log_listing_capped, log_listing_ids, log_night, logs_to_ical,
+    session_report, to_api_json, with_retry, ApiResponse, CappedListing, DebugEnvelope,
+    GetListRequest, JsonStyle, ListFormat, Log, NightlogError, PublicLog,
+};
 use serde::Serialize;
 
 /// Requests come into the runtime as unicode
 /// strings in json format, which can map to any structure that implements `serde::Deserialize`
 /// The runtime pays no attention to the contents of the request payload.
 
-/// This is a made-up example of what a response structure may look like.
-/// There is no restriction on what it can be. The runtime requires responses
-/// to be serialized into json. The runtime pays no attention
-/// to the contents of the response payload.
-#[allow(non_snake_case)]
+/// The count is always there, so that an empty listing can't be mistaken for an error.
+/// `capped` tells that there are more logs than the server returns at once.
 #[derive(Serialize)]
-struct Response {
-    statusCode: i32,
-    body: String,
+struct ListBody {
+    count: usize,
+    capped: bool,
+    logs: Vec<PublicLog>,
+}
+
+impl ListBody {
+    fn new(listing: CappedListing) -> ListBody {
+        ListBody {
+            count: listing.logs.len(),
+            capped: listing.capped,
+            logs: listing.logs.iter().map(Log::to_public).collect(),
+        }
+    }
+}
+
+/// Just the ids, for the clients diffing them against their own copy.
+#[derive(Serialize)]
+struct IdsBody {
+    count: usize,
+    ids: Vec<ObjectId>,
+}
+
+impl IdsBody {
+    fn new(ids: Vec<ObjectId>) -> IdsBody {
+        IdsBody {
+            count: ids.len(),
+            ids,
+        }
+    }
+}
+
+/// A body that can't be serialized is answered with a 500 telling so, instead of an
+/// opaque runtime error.
+fn build_response<T: Serialize>(body: &T, style: JsonStyle) -> ApiResponse {
+    match to_api_json(body, style) {
+        Ok(body) => ApiResponse::new(200, body),
+        Err(e) => {
+            let e = NightlogError::from(e);
+            tracing::error!("couldn't serialize the listing: {}", e);
+            e.to_response()
+        }
+    }
+}
+
+/// The body goes in a `DebugEnvelope` when the request has `debug` set, and is
+/// indented when it has `pretty` set.
+fn respond<T: Serialize>(list_req: &GetListRequest, body: &T) -> ApiResponse {
+    let style = JsonStyle {
+        pretty: list_req.pretty,
+    };
+    if list_req.debug {
+        let envelope = DebugEnvelope {
+            request: list_req,
+            result: body,
+        };
+        return build_response(&envelope, style);
+    }
+    build_response(body, style)
+}
+
+/// The logs as a calendar, the other response options only apply to json.
+fn ical_response(logs: &[Log]) -> ApiResponse {
+    ApiResponse::new(200, logs_to_ical(logs)).with_header("Content-Type", "text/calendar")
 }
 
 /// This is the main body for the function.
-async fn function_handler(event: LambdaEvent<GetListRequest>) -> Result<Response, Error> {
-    let mongodb_client = mongodb_connection().await?;
+async fn function_handler(event: LambdaEvent<GetListRequest>) -> Result<ApiResponse, Error> {
+    let mongodb_client = connect_with_retry().await?;
     let list_req = event.payload;
-    let res = log_listing(&mongodb_client, &list_req).await?;
-    let body;
-    match res.try_collect::<Vec<Log>>().await {
-        Ok(vector) => body = serde_json::to_string(&vector)?,
+    // with a night the logs are gathered in its session report
+    if let Some(night) = list_req.night {
+        let logs = with_retry(|| log_night(&mongodb_client, list_req.user_id(), night)).await?;
+        let report = session_report(&logs);
+        return Ok(respond(&list_req, &report));
+    }
+    if list_req.ids_only {
+        let ids = with_retry(|| log_listing_ids(&mongodb_client, &list_req)).await?;
+        return Ok(respond(&list_req, &IdsBody::new(ids)));
+    }
+    let resp = match with_retry(|| log_listing_capped(&mongodb_client, &list_req)).await {
+        Ok(listing) if list_req.format == ListFormat::Ical => ical_response(&listing.logs),
+        Ok(listing) => respond(&list_req, &ListBody::new(listing)),
         Err(e) => {
             return Err(format!(
                 "an error occurred in collecting user's logs in a vector: {}",
@@ -33,14 +106,9 @@ async fn function_handler(event: LambdaEvent<GetListRequest>) -> Result<Response
             )
             .into());
         }
-    }
-    // Prepare the response
-    let resp = Response {
-        statusCode: 200,
-        body,
     };
 
-    // Return `Response` (it will be serialized to JSON automatically by the runtime)
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
     Ok(resp)
 }
 
@@ -58,4 +126,136 @@ async fn main() -> Result<(), Error> {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use crate::{build_response, ical_response, respond, IdsBody, ListBody};
+    use chrono::NaiveDate;
+    use mongodb::bson::oid::ObjectId;
+    use nightlog_common::{
+        session_report, CappedListing, GetListRequest, JsonStyle, Log, ObservationRequest,
+    };
+    use serde::{Serialize, Serializer};
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("not serializable"))
+        }
+    }
+
+    #[test]
+    fn empty_list_body_test() {
+        let listing = CappedListing {
+            logs: Vec::new(),
+            capped: false,
+        };
+        let body = serde_json::to_value(ListBody::new(listing)).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"count": 0, "capped": false, "logs": []})
+        );
+    }
+
+    #[test]
+    fn list_body_count_test() {
+        let req = ObservationRequest {
+            user_id: "fake_id".into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let logs = vec![
+            Log::from_observation_request(&req),
+            Log::from_observation_request(&req),
+        ];
+        let listing = CappedListing { logs, capped: true };
+        let body = serde_json::to_value(ListBody::new(listing)).unwrap();
+        assert_eq!(body["count"], 2);
+        assert_eq!(body["capped"], true);
+        assert_eq!(body["logs"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn serialization_error_response_test() {
+        let resp = build_response(&Unserializable, JsonStyle::default());
+        assert_eq!(resp.statusCode, 500);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body["error"], "serialization");
+        assert!(body["message"]
+            .as_str()
+            .unwrap()
+            .contains("not serializable"));
+    }
+
+    #[test]
+    fn night_request_test() {
+        let list_req: GetListRequest = serde_json::from_value(serde_json::json!({
+            "userId": "fake_id",
+            "night": "2024-03-10"
+        }))
+        .unwrap();
+        assert_eq!(list_req.night, NaiveDate::from_ymd_opt(2024, 3, 10));
+
+        let body = build_response(&session_report(&[]), JsonStyle::default());
+        let report: serde_json::Value = serde_json::from_str(&body.body).unwrap();
+        assert_eq!(report["objectCount"], 0);
+    }
+
+    #[test]
+    fn ids_body_test() {
+        let id = ObjectId::new();
+        let body = serde_json::to_value(IdsBody::new(vec![id])).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"count": 1, "ids": [{"$oid": id.to_hex()}]})
+        );
+    }
+
+    #[test]
+    fn debug_echo_test() {
+        let ids = IdsBody::new(Vec::new());
+        let mut list_req: GetListRequest = serde_json::from_value(serde_json::json!({
+            "userId": "fake_id",
+            "idsOnly": true
+        }))
+        .unwrap();
+        let resp = respond(&list_req, &ids);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert!(body.get("request").is_none());
+        assert_eq!(body["count"], 0);
+
+        list_req.debug = true;
+        let resp = respond(&list_req, &ids);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body["request"]["userId"], "fake_id");
+        assert_eq!(body["request"]["idsOnly"], true);
+        assert_eq!(body["result"]["count"], 0);
+    }
+
+    #[test]
+    fn pretty_response_test() {
+        let ids = IdsBody::new(Vec::new());
+        let mut list_req: GetListRequest =
+            serde_json::from_value(serde_json::json!({"userId": "fake_id"})).unwrap();
+        let resp = respond(&list_req, &ids);
+        assert_eq!(resp.body, r#"{"count":0,"ids":[]}"#);
+
+        list_req.pretty = true;
+        let resp = respond(&list_req, &ids);
+        assert_eq!(resp.body, "{\n  \"count\": 0,\n  \"ids\": []\n}");
+    }
+
+    #[test]
+    fn ical_response_test() {
+        let req = ObservationRequest {
+            user_id: "fake_id".into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let resp = ical_response(&[Log::from_observation_request(&req)]);
+        assert_eq!(
+            resp.headers.get("Content-Type").map(String::as_str),
+            Some("text/calendar")
+        );
+        assert!(resp.body.contains("SUMMARY:M31\r\n"));
+    }
+}
@@ -1,33 +1,51 @@
+#![allow(clippy::empty_line_after_doc_comments)]
+
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use nightlog_common::{log_replacement, mongodb_connection, Log};
-use serde::Serialize;
+use nightlog_common::{
+    connect_with_retry, log_replacement, with_retry, ApiResponse, Log, NightlogError,
+    ReplaceLogRequest, CONFIG,
+};
 
 /// Requests come into the runtime as unicode
 /// strings in json format, which can map to any structure that implements `serde::Deserialize`
 /// The runtime pays no attention to the contents of the request payload.
 
-/// There is no restriction on what it can be. The runtime requires responses
-/// to be serialized into json. The runtime pays no attention
-/// to the contents of the response payload.
-#[allow(non_snake_case)]
-#[derive(Serialize)]
-struct Response {
-    statusCode: i32,
-    body: u64,
+/// Missing and foreign logs get their own status, with nothing modified.
+fn build_response(res: Result<u64, NightlogError>) -> Result<ApiResponse<u64>, Error> {
+    match res {
+        Ok(modified_count) => Ok(ApiResponse::new(200, modified_count)),
+        Err(e @ (NightlogError::NotFound | NightlogError::Forbidden)) => {
+            Ok(ApiResponse::new(e.status_code(), 0))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replacements dated in the future, as a wrong client clock makes them, are answered
+/// with a 400 before anything is modified.
+fn check_replacement_date(log: &Log, reject_future: bool) -> Result<(), ApiResponse<u64>> {
+    match log.check_date(reject_future) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(ApiResponse::new(400, 0)),
+    }
 }
 
 /// This is the main body for the function.
-async fn function_handler(event: LambdaEvent<Log>) -> Result<Response, Error> {
-    let mongodb_client = mongodb_connection().await?;
-    let log = event.payload;
-    let res = log_replacement(&log, &mongodb_client).await?;
+async fn function_handler(
+    event: LambdaEvent<ReplaceLogRequest>,
+) -> Result<ApiResponse<u64>, Error> {
+    let log = event.payload.to_log();
+    if let Err(resp) = check_replacement_date(&log, CONFIG.features.reject_future_dates) {
+        return Ok(resp);
+    }
+    let mongodb_client = connect_with_retry().await?;
+    let res = with_retry(|| log_replacement(&log, &mongodb_client))
+        .await
+        .map(|res| res.modified_count);
     // Prepare the response
-    let resp = Response {
-        statusCode: 200,
-        body: res.modified_count,
-    };
+    let resp = build_response(res)?;
 
-    // Return `Response` (it will be serialized to JSON automatically by the runtime)
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
     Ok(resp)
 }
 
@@ -45,4 +63,47 @@ async fn main() -> Result<(), Error> {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use crate::{build_response, check_replacement_date};
+    use chrono::{Duration, Utc};
+    use nightlog_common::{Log, NightlogError, ObservationRequest};
+
+    #[test]
+    fn replaced_response_test() {
+        let resp = build_response(Ok(1)).unwrap();
+        assert_eq!(resp.statusCode, 200);
+        assert_eq!(resp.body, 1);
+    }
+
+    #[test]
+    fn forbidden_response_test() {
+        let resp = build_response(Err(NightlogError::Forbidden)).unwrap();
+        assert_eq!(resp.statusCode, 403);
+        assert_eq!(resp.body, 0);
+    }
+
+    #[test]
+    fn not_found_response_test() {
+        let resp = build_response(Err(NightlogError::NotFound)).unwrap();
+        assert_eq!(resp.statusCode, 404);
+        assert_eq!(resp.body, 0);
+    }
+
+    #[test]
+    fn future_dated_replacement_test() {
+        let req = ObservationRequest {
+            user_id: "fake_id".into(),
+            object_name: "M31".to_string(),
+            ..Default::default()
+        };
+        let mut log = Log::from_observation_request(&req);
+        log.date = Utc::now() + Duration::days(1);
+        assert!(check_replacement_date(&log, false).is_ok());
+        let resp = check_replacement_date(&log, true).unwrap_err();
+        assert_eq!(resp.statusCode, 400);
+        assert_eq!(resp.body, 0);
+
+        log.date = Utc::now() - Duration::days(1);
+        assert!(check_replacement_date(&log, true).is_ok());
+    }
+}
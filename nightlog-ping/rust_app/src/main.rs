@@ -0,0 +1,39 @@
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nightlog_common::ApiResponse;
+
+// Requests come into the runtime as unicode
+// strings in json format, which can map to any structure that implements `serde::Deserialize`
+// The runtime pays no attention to the contents of the request payload.
+
+/// Liveness check: it answers without connecting to the database, so that the function
+/// can be told alive even while MongoDB is unreachable.
+async fn function_handler(_event: LambdaEvent<serde_json::Value>) -> Result<ApiResponse, Error> {
+    Ok(ApiResponse::new(200, "pong".to_string()))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::function_handler;
+    use lambda_runtime::{Context, LambdaEvent};
+
+    #[tokio::test]
+    async fn ping_test() {
+        let event = LambdaEvent::new(serde_json::json!({}), Context::default());
+        let resp = function_handler(event).await.unwrap();
+        assert_eq!(resp.statusCode, 200);
+        assert_eq!(resp.body, "pong");
+    }
+}
@@ -0,0 +1,44 @@
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nightlog_common::{observation_schema, ApiResponse};
+
+// Requests come into the runtime as unicode
+// strings in json format, which can map to any structure that implements `serde::Deserialize`
+// The runtime pays no attention to the contents of the request payload.
+
+/// The schema comes from the request struct alone, so the database isn't needed.
+async fn function_handler(_event: LambdaEvent<serde_json::Value>) -> Result<ApiResponse, Error> {
+    let body = serde_json::to_string(&observation_schema())?;
+    Ok(ApiResponse::new(200, body))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::function_handler;
+    use lambda_runtime::{Context, LambdaEvent};
+
+    #[tokio::test]
+    async fn schema_response_test() {
+        let event = LambdaEvent::new(serde_json::json!({}), Context::default());
+        let resp = function_handler(event).await.unwrap();
+        assert_eq!(resp.statusCode, 200);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert!(body["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|field| field["name"] == "objectName" && field["required"] == true));
+    }
+}
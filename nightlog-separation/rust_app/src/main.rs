@@ -0,0 +1,71 @@
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nightlog_common::{
+    connect_with_retry, log_separation, with_retry, ApiResponse, LogPairRequest, NightlogError,
+    Separation,
+};
+
+// Requests come into the runtime as unicode
+// strings in json format, which can map to any structure that implements `serde::Deserialize`
+// The runtime pays no attention to the contents of the request payload.
+
+/// The separation is the body, a missing log or coordinate gets its status with the
+/// error as body.
+fn build_response(res: Result<f64, NightlogError>) -> Result<ApiResponse, Error> {
+    match res {
+        Ok(separation_deg) => {
+            let body = serde_json::to_string(&Separation { separation_deg })?;
+            Ok(ApiResponse::new(200, body))
+        }
+        Err(e @ (NightlogError::NotFound | NightlogError::Invalid(_))) => Ok(e.to_response()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// This is the main body for the function.
+async fn function_handler(event: LambdaEvent<LogPairRequest>) -> Result<ApiResponse, Error> {
+    let mongodb_client = connect_with_retry().await?;
+    let separation_req = event.payload;
+    let res = with_retry(|| log_separation(&mongodb_client, &separation_req)).await;
+
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
+    build_response(res)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_response;
+    use nightlog_common::{FieldError, NightlogError};
+
+    #[test]
+    fn separation_response_test() {
+        let resp = build_response(Ok(0.197)).unwrap();
+        assert_eq!(resp.statusCode, 200);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body, serde_json::json!({"separationDeg": 0.197}));
+    }
+
+    #[test]
+    fn missing_coordinates_response_test() {
+        let e = NightlogError::Invalid(FieldError::new("coordinates", "missing"));
+        let resp = build_response(Err(e)).unwrap();
+        assert_eq!(resp.statusCode, 400);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body["error"], "invalid");
+
+        let resp = build_response(Err(NightlogError::NotFound)).unwrap();
+        assert_eq!(resp.statusCode, 404);
+    }
+}
@@ -0,0 +1,71 @@
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use nightlog_common::{
+    connect_with_retry, log_share_card, with_retry, ApiResponse, NightlogError, ShareCard,
+    ShareCardRequest,
+};
+
+// Requests come into the runtime as unicode
+// strings in json format, which can map to any structure that implements `serde::Deserialize`
+// The runtime pays no attention to the contents of the request payload.
+
+/// The card is the body, an unknown token gets its status with the error as body.
+fn build_response(res: Result<ShareCard, NightlogError>) -> Result<ApiResponse, Error> {
+    match res {
+        Ok(card) => Ok(ApiResponse::new(200, serde_json::to_string(&card)?)),
+        Err(e @ NightlogError::NotFound) => Ok(e.to_response()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// This is the main body for the function.
+async fn function_handler(event: LambdaEvent<ShareCardRequest>) -> Result<ApiResponse, Error> {
+    let mongodb_client = connect_with_retry().await?;
+    let share_req = event.payload;
+    let res = with_retry(|| log_share_card(&mongodb_client, &share_req)).await;
+
+    // Return `ApiResponse` (it will be serialized to JSON automatically by the runtime)
+    build_response(res)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_response;
+    use nightlog_common::{NightlogError, ShareCard};
+
+    #[test]
+    fn share_card_response_test() {
+        let card = ShareCard {
+            object_name: "M31".to_string(),
+            date: "2024-03-10".to_string(),
+            summary: "dust lane visible".to_string(),
+        };
+        let resp = build_response(Ok(card)).unwrap();
+        assert_eq!(resp.statusCode, 200);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"objectName": "M31", "date": "2024-03-10", "summary": "dust lane visible"})
+        );
+    }
+
+    #[test]
+    fn unknown_token_response_test() {
+        let resp = build_response(Err(NightlogError::NotFound)).unwrap();
+        assert_eq!(resp.statusCode, 404);
+        let body: serde_json::Value = serde_json::from_str(&resp.body).unwrap();
+        assert_eq!(body["error"], "not_found");
+    }
+}